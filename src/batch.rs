@@ -0,0 +1,137 @@
+//! Batch processing
+//!
+//! A batch node runs its `exec` once for every item of a list instead of once per execution. Each
+//! item is retried on its own using the node's retry policy. Items can be processed one after the
+//! other or concurrently, with results always kept in the order of the items.
+use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError, NodeLogic, NodeOutput};
+use crate::shared::SharedData;
+use async_trait::async_trait;
+use serde_json::Value;
+
+/// How a batch node processes its items
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchMode {
+    /// One item after the other
+    Sequential,
+    /// Up to `max_concurrency` items at once, at least one
+    ///
+    /// Async logic runs as Tokio tasks. Sync logic runs on worker threads.
+    Parallel { max_concurrency: usize },
+}
+
+/// Logic that maps item logic over a list stored in the shared data
+///
+/// The list is read from `items_key` and the results are stored as a list under `output_key`.
+/// Only `exec` and `exec_fallback` of the item logic are used, once per item.
+pub struct BatchNode<L> {
+    items_key: String,
+    output_key: String,
+    logic: L,
+}
+
+impl<L> BatchNode<L> {
+    /// Creates batch logic running `logic` on every item stored under `items_key`
+    ///
+    /// # Arguments
+    ///
+    /// * `items_key` - The shared data key holding the list of items
+    /// * `output_key` - The shared data key the list of results is stored under
+    /// * `logic` - The logic run on every item
+    pub fn new(items_key: &str, output_key: &str, logic: L) -> Self {
+        Self {
+            items_key: items_key.to_string(),
+            output_key: output_key.to_string(),
+            logic,
+        }
+    }
+
+    fn items(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        shared
+            .require::<Vec<Value>>(&self.items_key)
+            .map(Value::Array)
+    }
+
+    fn store(&self, shared: &SharedData, results: Value) -> Result<NodeOutput, NodeError> {
+        shared.set_value(self.output_key.clone(), results);
+        Ok(NodeOutput::default())
+    }
+}
+
+impl<L: NodeLogic + 'static> BatchNode<L> {
+    /// Creates a batch node with the given name running this logic
+    ///
+    /// Items are processed sequentially unless another mode is set with [`Node::with_batch`].
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?
+            .with_logic(self)
+            .with_batch(BatchMode::Sequential))
+    }
+}
+
+impl<L: AsyncNodeLogic + 'static> BatchNode<L> {
+    /// Creates a batch node with the given name running this async logic
+    ///
+    /// Items are processed sequentially unless another mode is set with [`Node::with_batch`].
+    pub fn into_async_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?
+            .with_async_logic(self)
+            .with_batch(BatchMode::Sequential))
+    }
+}
+
+impl<L: NodeLogic> NodeLogic for BatchNode<L> {
+    fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        self.items(shared)
+    }
+
+    fn exec(&self, item: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        self.logic.exec(item, ctx)
+    }
+
+    fn exec_fallback(
+        &self,
+        item: &Value,
+        error: NodeError,
+        ctx: &ExecContext,
+    ) -> Result<Value, NodeError> {
+        self.logic.exec_fallback(item, error, ctx)
+    }
+
+    fn post(
+        &self,
+        shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        self.store(shared, exec_res)
+    }
+}
+
+#[async_trait]
+impl<L: AsyncNodeLogic> AsyncNodeLogic for BatchNode<L> {
+    async fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        self.items(shared)
+    }
+
+    async fn exec(&self, item: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        self.logic.exec(item, ctx).await
+    }
+
+    async fn exec_fallback(
+        &self,
+        item: &Value,
+        error: NodeError,
+        ctx: &ExecContext,
+    ) -> Result<Value, NodeError> {
+        self.logic.exec_fallback(item, error, ctx).await
+    }
+
+    async fn post(
+        &self,
+        shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        self.store(shared, exec_res)
+    }
+}
@@ -1,21 +1,258 @@
 //! Core types and structures for LlmFlow
 //!
 //! This module contains the fundamental types used throughout the library.
-use std::sync::Arc;
+use crate::batch::BatchMode;
+use crate::llm::Usage;
+use crate::retry::{Backoff, RetryPolicy};
+use crate::shared::SharedData;
+use crate::usage::{Ledger, ModelCall};
+use crate::validation::ValidationReport;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-const MAX_RETRIES: u8 = 10;
-const MAX_WAIT_SECONDS: u8 = 60;
+pub(crate) const MAX_RETRIES: u8 = 10;
+pub(crate) const MAX_WAIT_SECONDS: u8 = 60;
 
-/// A node in the graph
+/// The action taken when a node does not choose one
+pub const DEFAULT_ACTION: &str = "default";
+
+/// The behaviour of a node
+///
+/// Execution happens in three phases: `prep` reads what the node needs from the shared data,
+/// `exec` does the actual work and is retried on failure, and `post` writes the results back.
+pub trait NodeLogic: Send + Sync {
+    /// Prepares the input for `exec` from the shared data
+    fn prep(&self, _shared: &SharedData) -> Result<Value, NodeError> {
+        Ok(Value::Null)
+    }
+
+    /// Performs the work of the node. Called again on failure until the retries run out.
+    fn exec(&self, prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError>;
+
+    /// Produces a result once `exec` has failed for good, given the error of the last attempt
+    ///
+    /// The returned value is passed to `post` in place of the result of `exec`. By default the
+    /// error is returned and the node fails.
+    fn exec_fallback(
+        &self,
+        _prep_res: &Value,
+        error: NodeError,
+        _ctx: &ExecContext,
+    ) -> Result<Value, NodeError> {
+        Err(error)
+    }
+
+    /// Stores the results of `exec` in the shared data and chooses the action to follow
+    fn post(
+        &self,
+        _shared: &SharedData,
+        _prep_res: Value,
+        _exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        Ok(NodeOutput::default())
+    }
+}
+
+/// The outcome of executing a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeOutput {
+    /// Follow the successor registered for this action
+    Action(String),
+}
+
+impl NodeOutput {
+    /// Returns the action used to select the next node
+    pub fn action(&self) -> &str {
+        match self {
+            NodeOutput::Action(action) => action,
+        }
+    }
+}
+
+impl Default for NodeOutput {
+    fn default() -> Self {
+        NodeOutput::Action(DEFAULT_ACTION.to_string())
+    }
+}
+
+/// The behaviour of a node whose phases are asynchronous
 ///
-/// This struct represents a node in the graph. It contains a name and a pointer to the next node in the graph.
+/// Mirrors [`NodeLogic`] for work such as HTTP calls that should not block the runtime. Nodes with
+/// async logic are run with [`Node::exec_async`].
+#[async_trait]
+pub trait AsyncNodeLogic: Send + Sync {
+    /// Prepares the input for `exec` from the shared data
+    async fn prep(&self, _shared: &SharedData) -> Result<Value, NodeError> {
+        Ok(Value::Null)
+    }
+
+    /// Performs the work of the node. Called again on failure until the retries run out.
+    async fn exec(&self, prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError>;
+
+    /// Produces a result once `exec` has failed for good, given the error of the last attempt
+    ///
+    /// The returned value is passed to `post` in place of the result of `exec`. By default the
+    /// error is returned and the node fails.
+    async fn exec_fallback(
+        &self,
+        _prep_res: &Value,
+        error: NodeError,
+        _ctx: &ExecContext,
+    ) -> Result<Value, NodeError> {
+        Err(error)
+    }
+
+    /// Stores the results of `exec` in the shared data and chooses the action to follow
+    async fn post(
+        &self,
+        _shared: &SharedData,
+        _prep_res: Value,
+        _exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        Ok(NodeOutput::default())
+    }
+}
+
+/// Logic used by nodes that have not been given any
+struct NoopLogic;
+
+impl NodeLogic for NoopLogic {
+    fn exec(&self, _prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+        Ok(Value::Null)
+    }
+}
+
+/// The logic held by a node
+#[derive(Clone)]
+enum Logic {
+    Sync(Arc<dyn NodeLogic>),
+    Async(Arc<dyn AsyncNodeLogic>),
+}
+
+/// Information available to a node while `exec` runs
 #[derive(Debug, Clone)]
+pub struct ExecContext {
+    node_name: String,
+    attempt: u8,
+    item_index: Option<usize>,
+    last_error: Option<Arc<NodeError>>,
+    shared: SharedData,
+    cancellation: Cancellation,
+    usage: Ledger,
+}
+
+impl ExecContext {
+    pub(crate) fn new(
+        node_name: &str,
+        shared: &SharedData,
+        cancellation: &Cancellation,
+        usage: &Ledger,
+    ) -> Self {
+        Self {
+            node_name: node_name.to_string(),
+            attempt: 0,
+            item_index: None,
+            last_error: None,
+            shared: shared.clone(),
+            cancellation: cancellation.clone(),
+            usage: usage.clone(),
+        }
+    }
+
+    /// Returns a context for a single attempt that can be cancelled on its own
+    fn for_attempt(&self) -> Self {
+        Self {
+            cancellation: self.cancellation.child(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a context for the item at `index` of a batch
+    fn for_item(&self, index: usize) -> Self {
+        Self {
+            item_index: Some(index),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the name of the node being executed
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    /// Returns the number of the current attempt, starting at 0
+    pub fn attempt(&self) -> u8 {
+        self.attempt
+    }
+
+    /// Returns the position of the item being processed when the node is a batch node
+    pub fn item_index(&self) -> Option<usize> {
+        self.item_index
+    }
+
+    /// Returns the error that failed the previous attempt, if this is a retry
+    pub fn last_error(&self) -> Option<&NodeError> {
+        self.last_error.as_deref()
+    }
+
+    /// Returns the shared data of the flow
+    pub fn shared(&self) -> &SharedData {
+        &self.shared
+    }
+
+    /// Returns true once the attempt has timed out and should stop
+    ///
+    /// Sync logic cannot be interrupted, so long running `exec` implementations should check this
+    /// regularly and return early. The result of a cancelled attempt is discarded.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Records the tokens used by a call to `model` made by this node
+    ///
+    /// The call counts towards the [`usage`](crate::flow::FlowResult::usage) of the flow run
+    /// executing the node, and of the runs that flow is nested in.
+    pub fn record_usage(&self, model: &str, usage: Usage) {
+        self.usage.record(ModelCall {
+            node: self.node_name.clone(),
+            model: model.to_string(),
+            prompt_tokens: u64::from(usage.prompt_tokens),
+            completion_tokens: u64::from(usage.completion_tokens),
+        });
+    }
+
+    /// Returns the cancellation of the attempt, inherited by flows it runs
+    pub(crate) fn cancellation(&self) -> &Cancellation {
+        &self.cancellation
+    }
+
+    /// Returns the ledger of the run executing the node, inherited by flows it runs
+    pub(crate) fn usage(&self) -> &Ledger {
+        &self.usage
+    }
+}
+
+/// A node in the graph
+///
+/// This struct represents a node in the graph. It contains a name, the logic it runs and the
+/// successor nodes to follow for each action.
+#[derive(Clone)]
 pub struct Node {
     name: String,
-    next: Option<Arc<Node>>,
-    max_retries: u8,
-    wait: u8,
+    successors: HashMap<String, Arc<Node>>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    batch: Option<BatchMode>,
+    logic: Logic,
 }
 
 #[derive(Error, Debug)]
@@ -34,6 +271,102 @@ pub enum NodeError {
 
     #[error("Execution retry limit reached after {attempts} attempts: {message}")]
     RetryLimitExceeded { attempts: u8, message: String },
+
+    #[error("Flow has no start node")]
+    MissingStartNode,
+
+    #[error("Missing shared data for key '{0}'")]
+    MissingSharedData(String),
+
+    #[error("Invalid shared data for key '{key}': {message}")]
+    InvalidSharedData { key: String, message: String },
+
+    #[error("Node '{0}' has async logic and must be run with exec_async")]
+    AsyncExecutionRequired(String),
+
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+
+    #[error("Validation failed: {0}")]
+    ValidationError(String),
+
+    #[error("Execution timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("Flow has no node named '{0}'")]
+    UnknownNode(String),
+
+    #[error("Loop limit of {limit} exceeded at node '{node}'")]
+    LoopLimitExceeded { node: String, limit: usize },
+
+    #[error("Invalid flow: {0}")]
+    InvalidFlow(ValidationReport),
+
+    #[error("Invalid flow definition: {0}")]
+    InvalidDefinition(String),
+
+    #[error("Unknown node type: {0}")]
+    UnknownNodeType(String),
+
+    #[error("Provider returned status {status}: {message}")]
+    ProviderError { status: u16, message: String },
+
+    #[error("Invalid template: {0}")]
+    InvalidTemplate(String),
+
+    #[error("Missing template variable '{0}'")]
+    MissingTemplateVariable(String),
+
+    #[error("Invalid model output: {message}")]
+    InvalidOutput { message: String, output: String },
+
+    #[error("Unknown tool: {0}")]
+    UnknownTool(String),
+
+    #[error("Invalid arguments for tool '{tool}': {message}")]
+    InvalidToolArguments { tool: String, message: String },
+
+    #[error("Prompt of {tokens} tokens exceeds the context window of {limit} tokens")]
+    ContextOverflow { tokens: usize, limit: usize },
+
+    #[error("Invalid tokenizer: {0}")]
+    InvalidTokenizer(String),
+
+    #[error("Node '{0}' was cancelled")]
+    Cancelled(String),
+}
+
+impl NodeError {
+    /// Returns true if retrying the failed operation could succeed
+    ///
+    /// Configuration, validation and shared data errors are not retryable, nor are provider
+    /// errors other than timeouts and server errors.
+    pub fn is_retryable(&self) -> bool {
+        if let NodeError::ProviderError { status, .. } = self {
+            return *status == 408 || *status >= 500;
+        }
+        !matches!(
+            self,
+            NodeError::InvalidRetryCount(..)
+                | NodeError::InvalidWaitTime(..)
+                | NodeError::EmptyNodeName
+                | NodeError::MissingStartNode
+                | NodeError::UnknownNode(_)
+                | NodeError::LoopLimitExceeded { .. }
+                | NodeError::InvalidFlow(_)
+                | NodeError::InvalidDefinition(_)
+                | NodeError::UnknownNodeType(_)
+                | NodeError::MissingSharedData(_)
+                | NodeError::InvalidSharedData { .. }
+                | NodeError::AsyncExecutionRequired(_)
+                | NodeError::ValidationError(_)
+                | NodeError::InvalidTemplate(_)
+                | NodeError::MissingTemplateVariable(_)
+                | NodeError::ContextOverflow { .. }
+                | NodeError::InvalidTokenizer(_)
+                | NodeError::Cancelled(_)
+        )
+    }
 }
 
 impl Node {
@@ -49,15 +382,34 @@ impl Node {
         }
         Ok(Self {
             name,
-            next: None,
-            max_retries: 0,
-            wait: 0,
+            successors: HashMap::new(),
+            retry: RetryPolicy::default(),
+            timeout: None,
+            batch: None,
+            logic: Logic::Sync(Arc::new(NoopLogic)),
         })
     }
 
+    /// Sets the logic run when the node is executed
+    pub fn with_logic<L: NodeLogic + 'static>(mut self, logic: L) -> Self {
+        self.logic = Logic::Sync(Arc::new(logic));
+        self
+    }
+
+    /// Sets async logic run when the node is executed with [`Node::exec_async`]
+    pub fn with_async_logic<L: AsyncNodeLogic + 'static>(mut self, logic: L) -> Self {
+        self.logic = Logic::Async(Arc::new(logic));
+        self
+    }
+
     /// Sets the next node and returns a new Node with updated next
-    pub fn with_next(mut self, node: Arc<Node>) -> Self {
-        self.next = Some(node);
+    pub fn with_next(self, node: Arc<Node>) -> Self {
+        self.with_successor(DEFAULT_ACTION, node)
+    }
+
+    /// Sets the node to follow when this node returns the given action
+    pub fn with_successor(mut self, action: &str, node: Arc<Node>) -> Self {
+        self.successors.insert(action.to_string(), node);
         self
     }
 
@@ -66,7 +418,7 @@ impl Node {
         if retries > MAX_RETRIES {
             return Err(NodeError::InvalidRetryCount(retries, MAX_RETRIES));
         }
-        self.max_retries = retries;
+        self.retry.set_max_retries(retries);
         Ok(self)
     }
 
@@ -75,41 +427,794 @@ impl Node {
         if wait > MAX_WAIT_SECONDS {
             return Err(NodeError::InvalidWaitTime(wait, MAX_WAIT_SECONDS));
         }
-        self.wait = wait;
+        self.retry = self
+            .retry
+            .with_backoff(Backoff::Constant(Duration::from_secs(u64::from(wait))));
         Ok(self)
     }
 
+    /// Sets the policy deciding how failed executions are retried
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Sets how long a single attempt of `exec` may run before it fails with
+    /// [`NodeError::Timeout`]. Timed out attempts are retried like any other failure.
+    ///
+    /// Async attempts are dropped when they time out. Sync attempts cannot be interrupted, so
+    /// they are cancelled cooperatively: [`ExecContext::is_cancelled`] turns true and the node
+    /// waits for `exec` to return before it retries or fails.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Makes the node a batch node
+    ///
+    /// `prep` must return an array. `exec` runs once per item, each with its own retries and
+    /// fallback, and `post` receives the array of results in order.
+    pub fn with_batch(mut self, mode: BatchMode) -> Self {
+        self.batch = Some(mode);
+        self
+    }
+
+    /// Returns the name of the node
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the policy deciding how failed executions are retried
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Returns how long a single attempt may run, if limited
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns how the node processes batches, if it is a batch node
+    pub fn batch_mode(&self) -> Option<&BatchMode> {
+        self.batch.as_ref()
+    }
+
+    /// Returns true if the node has async logic
+    pub fn is_async(&self) -> bool {
+        matches!(self.logic, Logic::Async(_))
+    }
+
     /// Returns a reference to the next node in the graph
     pub fn next(&self) -> Option<&Arc<Node>> {
-        self.next.as_ref()
+        self.successor(DEFAULT_ACTION)
+    }
+
+    /// Returns the node to follow for the given action
+    pub fn successor(&self, action: &str) -> Option<&Arc<Node>> {
+        self.successors.get(action)
+    }
+
+    /// Returns all successors of the node keyed by action
+    pub fn successors(&self) -> &HashMap<String, Arc<Node>> {
+        &self.successors
     }
 
     /// Executes the node's logic with retry capability
-    pub fn exec(&self) -> Result<(), NodeError> {
-        println!("Executing node {}", self.name);
-        let mut attempts = 0;
-        while attempts <= self.max_retries {
-            match self.execute_logic() {
-                Ok(_) => break,
-                Err(_) if attempts < self.max_retries => {
-                    std::thread::sleep(std::time::Duration::from_secs(self.wait as u64));
-                    attempts += 1;
-                    continue;
+    ///
+    /// Runs `prep` once, `exec` until it succeeds or the retries run out, then `post`. When the
+    /// retries run out, `exec_fallback` gets a chance to produce a result instead. Fails with
+    /// [`NodeError::AsyncExecutionRequired`] if the node has async logic.
+    pub fn exec(&self, shared: &SharedData) -> Result<NodeOutput, NodeError> {
+        self.run(shared, None, &Cancellation::default(), &Ledger::default())
+    }
+
+    /// Executes the node's logic with retry capability without blocking the runtime
+    ///
+    /// Works like [`Node::exec`] but waits between retries with `tokio::time::sleep`. Sync logic
+    /// is run inline, so nodes of both kinds can be mixed in an async flow.
+    pub async fn exec_async(&self, shared: &SharedData) -> Result<NodeOutput, NodeError> {
+        self.run_async(shared, None, &Cancellation::default(), &Ledger::default())
+            .await
+    }
+
+    /// Executes the node, giving up on attempts that would run past `deadline`
+    ///
+    /// Attempts are cancelled along with `cancellation`, set when the flow runs inside a sub-flow
+    /// attempt that timed out. Model calls are recorded in `usage`.
+    pub(crate) fn run(
+        &self,
+        shared: &SharedData,
+        deadline: Option<&Deadline>,
+        cancellation: &Cancellation,
+        usage: &Ledger,
+    ) -> Result<NodeOutput, NodeError> {
+        let Logic::Sync(logic) = &self.logic else {
+            return Err(NodeError::AsyncExecutionRequired(self.name.clone()));
+        };
+        tracing::debug!(node = %self.name, "executing node");
+        let prep_res = logic.prep(shared)?;
+        let ctx = ExecContext::new(&self.name, shared, cancellation, usage);
+        let exec_res = match &self.batch {
+            None => self.exec_with_retry(logic, &prep_res, ctx, deadline)?,
+            Some(BatchMode::Sequential) => {
+                let results = self
+                    .batch_items(&prep_res)?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| self.exec_with_retry(logic, item, ctx.for_item(i), deadline))
+                    .collect::<Result<_, _>>()?;
+                Value::Array(results)
+            }
+            Some(BatchMode::Parallel { max_concurrency }) => {
+                let items = self.batch_items(&prep_res)?;
+                Value::Array(self.exec_parallel(logic, items, &ctx, deadline, *max_concurrency)?)
+            }
+        };
+        logic.post(shared, prep_res, exec_res)
+    }
+
+    /// Async counterpart of [`Node::run`]
+    pub(crate) async fn run_async(
+        &self,
+        shared: &SharedData,
+        deadline: Option<&Deadline>,
+        cancellation: &Cancellation,
+        usage: &Ledger,
+    ) -> Result<NodeOutput, NodeError> {
+        tracing::debug!(node = %self.name, "executing node");
+        let prep_res = match &self.logic {
+            Logic::Sync(logic) => logic.prep(shared)?,
+            Logic::Async(logic) => logic.prep(shared).await?,
+        };
+        let ctx = ExecContext::new(&self.name, shared, cancellation, usage);
+        let exec_res = match &self.batch {
+            None => self.exec_with_retry_async(&prep_res, ctx, deadline).await?,
+            Some(BatchMode::Sequential) => {
+                let items = self.batch_items(&prep_res)?;
+                let mut results = Vec::with_capacity(items.len());
+                for (i, item) in items.iter().enumerate() {
+                    results.push(
+                        self.exec_with_retry_async(item, ctx.for_item(i), deadline)
+                            .await?,
+                    );
+                }
+                Value::Array(results)
+            }
+            Some(BatchMode::Parallel { max_concurrency }) => {
+                let items = self.batch_items(&prep_res)?.clone();
+                let results = self
+                    .exec_parallel_async(items, &ctx, deadline, *max_concurrency)
+                    .await?;
+                Value::Array(results)
+            }
+        };
+        match &self.logic {
+            Logic::Sync(logic) => logic.post(shared, prep_res, exec_res),
+            Logic::Async(logic) => logic.post(shared, prep_res, exec_res).await,
+        }
+    }
+
+    /// Runs `exec` on `input` until it succeeds or the retries run out, then `exec_fallback`
+    fn exec_with_retry(
+        &self,
+        logic: &Arc<dyn NodeLogic>,
+        input: &Value,
+        mut ctx: ExecContext,
+        deadline: Option<&Deadline>,
+    ) -> Result<Value, NodeError> {
+        let mut schedule = self.retry.schedule();
+        loop {
+            self.check_cancelled(&ctx)?;
+            let limit = self.attempt_limit(deadline)?;
+            match exec_with_timeout(logic, input, &ctx, limit) {
+                Ok(res) => return Ok(res),
+                Err(e) => {
+                    let Some(delay) = schedule.next_delay(&e) else {
+                        return logic
+                            .exec_fallback(input, e, &ctx)
+                            .map_err(|e| schedule.final_error(e));
+                    };
+                    check_deadline(deadline, delay)?;
+                    tracing::debug!(node = %self.name, ?delay, "retrying node");
+                    std::thread::sleep(delay);
+                    ctx.attempt = schedule.attempts();
+                    ctx.last_error = Some(Arc::new(e));
                 }
+            }
+        }
+    }
+
+    /// Async counterpart of [`Node::exec_with_retry`]
+    async fn exec_with_retry_async(
+        &self,
+        input: &Value,
+        mut ctx: ExecContext,
+        deadline: Option<&Deadline>,
+    ) -> Result<Value, NodeError> {
+        let mut schedule = self.retry.schedule();
+        loop {
+            self.check_cancelled(&ctx)?;
+            let limit = self.attempt_limit(deadline)?;
+            let res = match &self.logic {
+                Logic::Sync(logic) => exec_blocking_with_timeout(logic, input, &ctx, limit).await,
+                Logic::Async(logic) => match limit {
+                    Some(limit) => tokio::time::timeout(limit, logic.exec(input, &ctx))
+                        .await
+                        .unwrap_or(Err(NodeError::Timeout(limit))),
+                    None => logic.exec(input, &ctx).await,
+                },
+            };
+            match res {
+                Ok(res) => return Ok(res),
                 Err(e) => {
-                    return Err(NodeError::RetryLimitExceeded {
-                        attempts,
-                        message: e.to_string(),
-                    });
+                    let Some(delay) = schedule.next_delay(&e) else {
+                        let fallback = match &self.logic {
+                            Logic::Sync(logic) => logic.exec_fallback(input, e, &ctx),
+                            Logic::Async(logic) => logic.exec_fallback(input, e, &ctx).await,
+                        };
+                        return fallback.map_err(|e| schedule.final_error(e));
+                    };
+                    check_deadline(deadline, delay)?;
+                    tracing::debug!(node = %self.name, ?delay, "retrying node");
+                    tokio::time::sleep(delay).await;
+                    ctx.attempt = schedule.attempts();
+                    ctx.last_error = Some(Arc::new(e));
                 }
             }
         }
-        Ok(())
     }
 
-    /// Internal method to execute the node's actual logic
-    fn execute_logic(&self) -> Result<(), NodeError> {
-        // Placeholder for actual node execution logic
+    /// Runs `exec` on every item using up to `max_concurrency` threads
+    ///
+    /// Stops picking up new items after the first failure.
+    fn exec_parallel(
+        &self,
+        logic: &Arc<dyn NodeLogic>,
+        items: &[Value],
+        ctx: &ExecContext,
+        deadline: Option<&Deadline>,
+        max_concurrency: usize,
+    ) -> Result<Vec<Value>, NodeError> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let results: Vec<OnceLock<Result<Value, NodeError>>> =
+            items.iter().map(|_| OnceLock::new()).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..max_concurrency.clamp(1, items.len().max(1)) {
+                scope.spawn(|| {
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break;
+                        };
+                        let res = self.exec_with_retry(logic, item, ctx.for_item(i), deadline);
+                        if res.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        let _ = results[i].set(res);
+                    }
+                });
+            }
+        });
+        results
+            .into_iter()
+            .filter_map(OnceLock::into_inner)
+            .collect()
+    }
+
+    /// Runs `exec` on every item with up to `max_concurrency` Tokio tasks at once
+    ///
+    /// Remaining tasks are aborted after the first failure.
+    async fn exec_parallel_async(
+        &self,
+        items: Vec<Value>,
+        ctx: &ExecContext,
+        deadline: Option<&Deadline>,
+        max_concurrency: usize,
+    ) -> Result<Vec<Value>, NodeError> {
+        let node = Arc::new(self.clone());
+        let deadline = deadline.copied();
+        if let Logic::Sync(logic) = &self.logic {
+            let (logic, ctx) = (logic.clone(), ctx.clone());
+            return tokio::task::spawn_blocking(move || {
+                node.exec_parallel(&logic, &items, &ctx, deadline.as_ref(), max_concurrency)
+            })
+            .await
+            .map_err(|e| NodeError::ExecutionError(e.to_string()))?;
+        }
+
+        let semaphore = Arc::new(Semaphore::new(max_concurrency.max(1)));
+        let mut results = vec![Value::Null; items.len()];
+        let mut tasks = JoinSet::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let (node, semaphore, ctx) = (node.clone(), semaphore.clone(), ctx.for_item(i));
+            tasks.spawn(async move {
+                let _permit = semaphore
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| NodeError::ExecutionError(e.to_string()))?;
+                let res = node
+                    .exec_with_retry_async(&item, ctx, deadline.as_ref())
+                    .await?;
+                Ok::<_, NodeError>((i, res))
+            });
+        }
+        while let Some(joined) = tasks.join_next().await {
+            let (i, res) = joined.map_err(|e| NodeError::ExecutionError(e.to_string()))??;
+            results[i] = res;
+        }
+        Ok(results)
+    }
+
+    /// Returns the items of a batch node's `prep` result
+    fn batch_items<'a>(&self, prep_res: &'a Value) -> Result<&'a Vec<Value>, NodeError> {
+        prep_res.as_array().ok_or_else(|| {
+            NodeError::ValidationError(format!(
+                "batch node '{}' expects prep to return an array",
+                self.name
+            ))
+        })
+    }
+
+    /// Fails if the attempt running the flow of this node was cancelled
+    fn check_cancelled(&self, ctx: &ExecContext) -> Result<(), NodeError> {
+        if ctx.is_cancelled() {
+            return Err(NodeError::Cancelled(self.name.clone()));
+        }
         Ok(())
     }
+
+    /// Returns how long the next attempt may run, or an error if the deadline has passed
+    fn attempt_limit(&self, deadline: Option<&Deadline>) -> Result<Option<Duration>, NodeError> {
+        let Some(deadline) = deadline else {
+            return Ok(self.timeout);
+        };
+        let remaining = deadline.remaining();
+        if remaining.is_zero() {
+            return Err(deadline.error());
+        }
+        Ok(Some(self.timeout.map_or(remaining, |t| t.min(remaining))))
+    }
+}
+
+/// A point in time by which a flow must finish
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// Creates a deadline `timeout` from now
+    pub(crate) fn after(timeout: Duration) -> Self {
+        Self {
+            at: Instant::now() + timeout,
+            timeout,
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    fn error(&self) -> NodeError {
+        NodeError::Timeout(self.timeout)
+    }
+}
+
+/// Cancels an attempt and the attempts of the flows it runs
+///
+/// Each attempt gets a flag of its own and sees the flags of the attempts it runs within.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cancellation {
+    flags: Vec<Arc<AtomicBool>>,
+}
+
+impl Cancellation {
+    /// Returns a cancellation that is also cancelled with this one
+    fn child(&self) -> Self {
+        let mut flags = self.flags.clone();
+        flags.push(Arc::default());
+        Self { flags }
+    }
+
+    fn cancel(&self) {
+        if let Some(flag) = self.flags.last() {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.flags.iter().any(|flag| flag.load(Ordering::Relaxed))
+    }
+}
+
+/// Fails if waiting `delay` before the next attempt would run past the deadline
+fn check_deadline(deadline: Option<&Deadline>, delay: Duration) -> Result<(), NodeError> {
+    match deadline {
+        Some(deadline) if deadline.remaining() <= delay => Err(deadline.error()),
+        _ => Ok(()),
+    }
+}
+
+/// Runs sync `exec` on its own thread so the attempt can be cancelled after `limit`
+///
+/// A timed out attempt is cancelled and waited for, so it never overlaps the next attempt, and
+/// its result is discarded.
+fn exec_with_timeout(
+    logic: &Arc<dyn NodeLogic>,
+    prep_res: &Value,
+    ctx: &ExecContext,
+    limit: Option<Duration>,
+) -> Result<Value, NodeError> {
+    let Some(limit) = limit else {
+        return logic.exec(prep_res, ctx);
+    };
+    let ctx = ctx.for_attempt();
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        let attempt = scope.spawn(|| {
+            let res = logic.exec(prep_res, &ctx);
+            let _ = tx.send(());
+            res
+        });
+        let timed_out = matches!(rx.recv_timeout(limit), Err(RecvTimeoutError::Timeout));
+        if timed_out {
+            ctx.cancellation.cancel();
+        }
+        let res = attempt
+            .join()
+            .map_err(|_| NodeError::ExecutionError("node logic panicked".to_string()))?;
+        if timed_out {
+            return Err(NodeError::Timeout(limit));
+        }
+        res
+    })
+}
+
+/// Runs sync `exec` inline, or on the blocking pool when it has to be cancelled after `limit`
+async fn exec_blocking_with_timeout(
+    logic: &Arc<dyn NodeLogic>,
+    prep_res: &Value,
+    ctx: &ExecContext,
+    limit: Option<Duration>,
+) -> Result<Value, NodeError> {
+    let Some(limit) = limit else {
+        return logic.exec(prep_res, ctx);
+    };
+    let ctx = ctx.for_attempt();
+    let cancellation = ctx.cancellation.clone();
+    let (logic, prep_res) = (logic.clone(), prep_res.clone());
+    let mut task = tokio::task::spawn_blocking(move || logic.exec(&prep_res, &ctx));
+    match tokio::time::timeout(limit, &mut task).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => Err(NodeError::ExecutionError(e.to_string())),
+        Err(_) => {
+            cancellation.cancel();
+            let _ = task.await;
+            Err(NodeError::Timeout(limit))
+        }
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("name", &self.name)
+            .field("successors", &self.successors)
+            .field("retry", &self.retry)
+            .field("timeout", &self.timeout)
+            .field("batch", &self.batch)
+            .field("is_async", &self.is_async())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::BatchNode;
+    use crate::flow::{Flow, SubFlow};
+
+    /// Sleeps for `duration`, optionally stopping early once cancelled, and counts attempts
+    struct Slow {
+        duration: Duration,
+        cooperative: bool,
+        running: Arc<AtomicUsize>,
+        overlapped: Arc<AtomicBool>,
+        finished: Arc<AtomicUsize>,
+    }
+
+    impl Slow {
+        fn new(duration: Duration, cooperative: bool) -> Self {
+            Self {
+                duration,
+                cooperative,
+                running: Arc::default(),
+                overlapped: Arc::default(),
+                finished: Arc::default(),
+            }
+        }
+    }
+
+    impl NodeLogic for Slow {
+        fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+            if self.running.fetch_add(1, Ordering::SeqCst) > 0 {
+                self.overlapped.store(true, Ordering::SeqCst);
+            }
+            let started = Instant::now();
+            while started.elapsed() < self.duration && !(self.cooperative && ctx.is_cancelled()) {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            ctx.shared().set_value("written", ctx.attempt());
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.finished.fetch_add(1, Ordering::SeqCst);
+            Ok(Value::Null)
+        }
+    }
+
+    fn slow_node(logic: Slow) -> Node {
+        Node::new(Some("slow"))
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new(2).unwrap())
+            .with_timeout(Duration::from_millis(50))
+            .with_logic(logic)
+    }
+
+    #[test]
+    fn timed_out_sync_attempts_never_overlap() {
+        for cooperative in [true, false] {
+            let logic = Slow::new(Duration::from_millis(150), cooperative);
+            let (overlapped, finished) = (logic.overlapped.clone(), logic.finished.clone());
+            let err = slow_node(logic).exec(&SharedData::new()).unwrap_err();
+            assert!(matches!(
+                err,
+                NodeError::RetryLimitExceeded { attempts: 2, .. }
+            ));
+            assert!(!overlapped.load(Ordering::SeqCst));
+            assert_eq!(finished.load(Ordering::SeqCst), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_sync_attempts_never_overlap_async() {
+        let logic = Slow::new(Duration::from_millis(150), false);
+        let (overlapped, finished) = (logic.overlapped.clone(), logic.finished.clone());
+        let err = slow_node(logic)
+            .exec_async(&SharedData::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NodeError::RetryLimitExceeded { .. }));
+        assert!(!overlapped.load(Ordering::SeqCst));
+        assert_eq!(finished.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn cooperative_attempts_stop_when_cancelled() {
+        let logic = Slow::new(Duration::from_secs(10), true);
+        let started = Instant::now();
+        let node = Node::new(Some("slow"))
+            .unwrap()
+            .with_timeout(Duration::from_millis(50))
+            .with_logic(logic);
+        let err = node.exec(&SharedData::new()).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Marks that it ran
+    struct Mark;
+
+    impl NodeLogic for Mark {
+        fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+            ctx.shared().set_value("marked", true);
+            Ok(Value::Null)
+        }
+    }
+
+    #[test]
+    fn timed_out_sub_flow_stops_before_its_next_node() {
+        let mut inner = Flow::new();
+        let first = Node::new(Some("first"))
+            .unwrap()
+            .with_logic(Slow::new(Duration::from_millis(150), false));
+        let second = Node::new(Some("second")).unwrap().with_logic(Mark);
+        inner.add_node(first.with_next(Arc::new(second)));
+        inner.start_at("first");
+
+        let mut flow = Flow::new().with_timeout(Duration::from_millis(50));
+        flow.add_node(SubFlow::new(inner).into_node("sub").unwrap());
+        flow.start_at("sub");
+        let shared = SharedData::new();
+        let err = flow.run(shared.clone()).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(shared.contains_key("written"));
+        assert!(!shared.contains_key("marked"));
+    }
+
+    /// Counts the items being processed, the most at once and the items that finished
+    #[derive(Default)]
+    struct Gauge {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+        finished: AtomicUsize,
+    }
+
+    impl Gauge {
+        fn enter(&self) {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+        }
+
+        fn leave(&self) {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.finished.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Sleeps for the number of milliseconds of its item and returns it, failing on null
+    #[derive(Clone, Default)]
+    struct Sleep(Arc<Gauge>);
+
+    impl Sleep {
+        fn millis(item: &Value) -> Result<u64, NodeError> {
+            item.as_u64()
+                .ok_or_else(|| NodeError::ValidationError(format!("bad item {item}")))
+        }
+    }
+
+    impl NodeLogic for Sleep {
+        fn exec(&self, item: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+            let millis = Self::millis(item)?;
+            self.0.enter();
+            std::thread::sleep(Duration::from_millis(millis));
+            self.0.leave();
+            Ok(item.clone())
+        }
+    }
+
+    #[async_trait]
+    impl AsyncNodeLogic for Sleep {
+        async fn exec(&self, item: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+            let millis = Self::millis(item)?;
+            self.0.enter();
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            self.0.leave();
+            Ok(item.clone())
+        }
+    }
+
+    const PARALLEL: BatchMode = BatchMode::Parallel { max_concurrency: 2 };
+
+    fn items(items: Value) -> SharedData {
+        let shared = SharedData::new();
+        shared.set_value("items", items);
+        shared
+    }
+
+    #[test]
+    fn parallel_batches_keep_item_order_within_the_limit() {
+        let logic = Sleep::default();
+        let node = BatchNode::new("items", "results", logic.clone())
+            .into_node("batch")
+            .unwrap()
+            .with_batch(PARALLEL);
+        let shared = items(serde_json::json!([40, 10, 30, 0, 20]));
+        node.exec(&shared).unwrap();
+
+        assert_eq!(shared.get_value("results"), shared.get_value("items"));
+        assert_eq!(logic.0.peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn async_parallel_batches_keep_item_order_within_the_limit() {
+        let logic = Sleep::default();
+        let node = BatchNode::new("items", "results", logic.clone())
+            .into_async_node("batch")
+            .unwrap()
+            .with_batch(PARALLEL);
+        let shared = items(serde_json::json!([40, 10, 30, 0, 20]));
+        node.exec_async(&shared).await.unwrap();
+
+        assert_eq!(shared.get_value("results"), shared.get_value("items"));
+        assert_eq!(logic.0.peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn sync_parallel_batches_run_async_within_the_limit() {
+        let logic = Sleep::default();
+        let node = BatchNode::new("items", "results", logic.clone())
+            .into_node("batch")
+            .unwrap()
+            .with_batch(PARALLEL);
+        let shared = items(serde_json::json!([40, 10, 30, 0, 20]));
+        node.exec_async(&shared).await.unwrap();
+
+        assert_eq!(shared.get_value("results"), shared.get_value("items"));
+        assert_eq!(logic.0.peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn parallel_batches_stop_after_the_first_failure() {
+        let logic = Sleep::default();
+        let node = BatchNode::new("items", "results", logic.clone())
+            .into_node("batch")
+            .unwrap()
+            .with_batch(BatchMode::Parallel { max_concurrency: 1 });
+        let shared = items(serde_json::json!([null, 10, 10, 10]));
+        let err = node.exec(&shared).unwrap_err();
+
+        assert!(matches!(err, NodeError::ValidationError(_)), "{err}");
+        assert_eq!(logic.0.finished.load(Ordering::SeqCst), 0);
+        assert!(!shared.contains_key("results"));
+    }
+
+    #[tokio::test]
+    async fn async_parallel_batches_stop_after_the_first_failure() {
+        let logic = Sleep::default();
+        let node = BatchNode::new("items", "results", logic.clone())
+            .into_async_node("batch")
+            .unwrap()
+            .with_batch(BatchMode::Parallel { max_concurrency: 1 });
+        let shared = items(serde_json::json!([null, 100, 100, 100]));
+        let err = node.exec_async(&shared).await.unwrap_err();
+
+        assert!(matches!(err, NodeError::ValidationError(_)), "{err}");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(logic.0.finished.load(Ordering::SeqCst), 0);
+    }
+
+    /// Fails every attempt, then falls back to a value describing the last error
+    #[derive(Default)]
+    struct Failing {
+        attempts: AtomicUsize,
+    }
+
+    impl NodeLogic for Failing {
+        fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            Err(NodeError::ExecutionError(format!(
+                "attempt {}",
+                ctx.attempt()
+            )))
+        }
+
+        fn exec_fallback(
+            &self,
+            _prep_res: &Value,
+            error: NodeError,
+            _ctx: &ExecContext,
+        ) -> Result<Value, NodeError> {
+            let attempts = self.attempts.load(Ordering::SeqCst);
+            Ok(Value::from(format!("{attempts} attempts, last: {error}")))
+        }
+
+        fn post(
+            &self,
+            shared: &SharedData,
+            _prep_res: Value,
+            exec_res: Value,
+        ) -> Result<NodeOutput, NodeError> {
+            shared.set_value("result", exec_res);
+            Ok(NodeOutput::default())
+        }
+    }
+
+    #[test]
+    fn falls_back_once_retries_are_exhausted() {
+        let node = Node::new(Some("failing"))
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new(2).unwrap())
+            .with_logic(Failing::default());
+        let shared = SharedData::new();
+        node.exec(&shared).unwrap();
+
+        assert_eq!(
+            shared.get_value("result"),
+            Some(Value::from(
+                "3 attempts, last: Node execution failed: attempt 2"
+            ))
+        );
+    }
 }
@@ -0,0 +1,318 @@
+//! Declarative flow definitions
+//!
+//! A [`FlowDefinition`] describes a flow as data: its nodes by name and type, their retry
+//! settings and the action edges between them. The logic of each node type is registered in a
+//! [`NodeRegistry`], so a flow can be changed by editing a JSON (or, with the `yaml` feature,
+//! YAML) file without recompiling.
+//!
+//! ```json
+//! {
+//!   "start": "classify",
+//!   "nodes": [
+//!     { "name": "classify", "type": "classifier", "max_retries": 2,
+//!       "edges": { "approve": "notify", "reject": "archive" } },
+//!     { "name": "notify", "type": "email", "params": { "to": "team@example.com" } },
+//!     { "name": "archive", "type": "archiver" }
+//!   ]
+//! }
+//! ```
+use crate::core::{AsyncNodeLogic, Node, NodeError, NodeLogic};
+use crate::flow::Flow;
+use crate::usage::PriceTable;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A flow described as data
+///
+/// Unknown fields are rejected, so a misspelled setting fails to parse instead of being ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlowDefinition {
+    /// Name of the node the flow starts from
+    pub start: String,
+    /// Nodes of the flow
+    pub nodes: Vec<NodeDefinition>,
+    /// Max time a whole run may take, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Max number of nodes a run may execute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<usize>,
+    /// Max number of times a run may execute the same node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_visits: Option<usize>,
+    /// Prices of the models called by the nodes, per million tokens
+    #[serde(default, skip_serializing_if = "PriceTable::is_empty")]
+    pub prices: PriceTable,
+}
+
+/// A node described as data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeDefinition {
+    /// Name of the node, unique within the flow
+    pub name: String,
+    /// Type of the node, as registered in the [`NodeRegistry`]
+    #[serde(rename = "type")]
+    pub node_type: String,
+    /// Max number of retries
+    #[serde(default)]
+    pub max_retries: u8,
+    /// Wait time in secs between retries
+    #[serde(default)]
+    pub wait: u8,
+    /// Max time a single attempt may take, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Settings passed to the factory of the node type
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+    /// Names of the nodes to follow, keyed by action
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub edges: BTreeMap<String, String>,
+}
+
+impl FlowDefinition {
+    /// Parses a definition from JSON
+    pub fn from_json(json: &str) -> Result<Self, NodeError> {
+        serde_json::from_str(json).map_err(|e| NodeError::InvalidDefinition(e.to_string()))
+    }
+
+    /// Serializes the definition to pretty-printed JSON
+    pub fn to_json(&self) -> Result<String, NodeError> {
+        serde_json::to_string_pretty(self).map_err(|e| NodeError::InvalidDefinition(e.to_string()))
+    }
+
+    /// Parses a definition from YAML
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(yaml: &str) -> Result<Self, NodeError> {
+        serde_yaml::from_str(yaml).map_err(|e| NodeError::InvalidDefinition(e.to_string()))
+    }
+
+    /// Serializes the definition to YAML
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> Result<String, NodeError> {
+        serde_yaml::to_string(self).map_err(|e| NodeError::InvalidDefinition(e.to_string()))
+    }
+
+    /// Reads a definition from a file, picking the format from its extension
+    ///
+    /// `.yaml` and `.yml` files are read as YAML when the `yaml` feature is enabled, everything
+    /// else as JSON.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, NodeError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            NodeError::InvalidDefinition(format!("cannot read {}: {e}", path.display()))
+        })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            #[cfg(feature = "yaml")]
+            Some("yaml" | "yml") => Self::from_yaml(&contents),
+            _ => Self::from_json(&contents),
+        }
+    }
+
+    /// Builds the flow using the node types registered in `registry`
+    ///
+    /// The resulting flow is validated, so a definition with dangling edges, duplicate names or
+    /// other errors fails with [`NodeError::InvalidFlow`].
+    pub fn build(&self, registry: &NodeRegistry) -> Result<Flow, NodeError> {
+        let mut flow = Flow::new().with_prices(self.prices.clone());
+        if let Some(timeout) = self.timeout_ms {
+            flow = flow.with_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(max_steps) = self.max_steps {
+            flow = flow.with_max_steps(max_steps);
+        }
+        if let Some(max_visits) = self.max_visits {
+            flow = flow.with_max_visits(max_visits);
+        }
+        for definition in &self.nodes {
+            flow.add_node(registry.create(definition)?);
+            for (action, to) in &definition.edges {
+                flow.connect(&definition.name, action, to);
+            }
+        }
+        flow.start_at(&self.start);
+        flow.validate().into_result()?;
+        Ok(flow)
+    }
+}
+
+type Factory = Arc<dyn Fn(Node, &Value) -> Result<Node, NodeError> + Send + Sync>;
+
+/// The node types available to flow definitions
+#[derive(Clone, Default)]
+pub struct NodeRegistry {
+    factories: HashMap<String, Factory>,
+}
+
+impl NodeRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a node type whose logic is created by `factory` from the node's params
+    pub fn register<L, F>(&mut self, node_type: &str, factory: F) -> &mut Self
+    where
+        L: NodeLogic + 'static,
+        F: Fn(&Value) -> Result<L, NodeError> + Send + Sync + 'static,
+    {
+        self.factories.insert(
+            node_type.to_string(),
+            Arc::new(move |node, params| Ok(node.with_logic(factory(params)?))),
+        );
+        self
+    }
+
+    /// Registers a node type whose async logic is created by `factory` from the node's params
+    pub fn register_async<L, F>(&mut self, node_type: &str, factory: F) -> &mut Self
+    where
+        L: AsyncNodeLogic + 'static,
+        F: Fn(&Value) -> Result<L, NodeError> + Send + Sync + 'static,
+    {
+        self.factories.insert(
+            node_type.to_string(),
+            Arc::new(move |node, params| Ok(node.with_async_logic(factory(params)?))),
+        );
+        self
+    }
+
+    /// Returns true if `node_type` is registered
+    pub fn contains(&self, node_type: &str) -> bool {
+        self.factories.contains_key(node_type)
+    }
+
+    /// Creates the node described by `definition`
+    pub fn create(&self, definition: &NodeDefinition) -> Result<Node, NodeError> {
+        let factory = self
+            .factories
+            .get(&definition.node_type)
+            .ok_or_else(|| NodeError::UnknownNodeType(definition.node_type.clone()))?;
+        let mut node = Node::new(Some(&definition.name))?
+            .with_retries(definition.max_retries)?
+            .with_wait(definition.wait)?;
+        if let Some(timeout) = definition.timeout_ms {
+            node = node.with_timeout(Duration::from_millis(timeout));
+        }
+        factory(node, &definition.params)
+    }
+}
+
+impl fmt::Debug for NodeRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut types: Vec<&String> = self.factories.keys().collect();
+        types.sort();
+        f.debug_struct("NodeRegistry")
+            .field("types", &types)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ExecContext, NodeOutput};
+    use crate::shared::SharedData;
+    use serde_json::json;
+
+    const DEFINITION: &str = r#"{
+        "start": "greet",
+        "max_steps": 10,
+        "nodes": [
+            { "name": "greet", "type": "set", "max_retries": 2,
+              "params": { "key": "greeting", "value": "hello" },
+              "edges": { "default": "done" } },
+            { "name": "done", "type": "set", "params": { "key": "done", "value": true } }
+        ]
+    }"#;
+
+    /// Sets the value given in its params
+    struct Set {
+        key: String,
+        value: Value,
+    }
+
+    impl NodeLogic for Set {
+        fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+            ctx.shared().set_value(self.key.clone(), self.value.clone());
+            Ok(Value::Null)
+        }
+    }
+
+    fn registry() -> NodeRegistry {
+        let mut registry = NodeRegistry::new();
+        registry.register("set", |params| {
+            Ok(Set {
+                key: params["key"].as_str().unwrap_or_default().to_string(),
+                value: params["value"].clone(),
+            })
+        });
+        registry
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let definition = FlowDefinition::from_json(DEFINITION).unwrap();
+        assert_eq!(definition.nodes[0].max_retries, 2);
+        assert_eq!(definition.nodes[1].edges, BTreeMap::new());
+        let json = definition.to_json().unwrap();
+        assert_eq!(FlowDefinition::from_json(&json).unwrap(), definition);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let json = r#"{ "start": "a", "nodes": [{ "name": "a", "type": "x", "max_retires": 5 }] }"#;
+        let err = FlowDefinition::from_json(json).unwrap_err();
+        assert!(
+            matches!(&err, NodeError::InvalidDefinition(message) if message.contains("max_retires")),
+            "{err}"
+        );
+        let json = r#"{ "start": "a", "nodes": [], "max_step": 5 }"#;
+        let err = FlowDefinition::from_json(json).unwrap_err();
+        assert!(matches!(err, NodeError::InvalidDefinition(_)));
+    }
+
+    #[test]
+    fn builds_registered_node_types() {
+        let flow = FlowDefinition::from_json(DEFINITION)
+            .unwrap()
+            .build(&registry())
+            .unwrap();
+        assert_eq!(flow.max_steps(), 10);
+        assert_eq!(flow.node("greet").unwrap().retry_policy().max_retries(), 2);
+
+        let result = flow.run(SharedData::new()).unwrap();
+        assert_eq!(result.last_node, "done");
+        assert_eq!(result.output.action(), NodeOutput::default().action());
+        assert_eq!(result.shared.get_value("greeting"), Some(json!("hello")));
+        assert_eq!(result.shared.get_value("done"), Some(json!(true)));
+    }
+
+    #[test]
+    fn fails_on_unknown_node_types() {
+        let json = r#"{ "start": "a", "nodes": [{ "name": "a", "type": "missing" }] }"#;
+        let err = FlowDefinition::from_json(json)
+            .unwrap()
+            .build(&registry())
+            .unwrap_err();
+        assert!(matches!(err, NodeError::UnknownNodeType(name) if name == "missing"));
+    }
+
+    #[test]
+    fn fails_on_dangling_edges() {
+        let json = r#"{ "start": "a", "nodes": [
+            { "name": "a", "type": "set", "edges": { "next": "nowhere" } }
+        ] }"#;
+        let err = FlowDefinition::from_json(json)
+            .unwrap()
+            .build(&registry())
+            .unwrap_err();
+        assert!(matches!(err, NodeError::InvalidFlow(_)), "{err}");
+    }
+}
@@ -0,0 +1,185 @@
+//! Diagrams of flows
+//!
+//! Renders a flow as Graphviz DOT or Mermaid, showing every node with its retry settings and
+//! every edge with the action that selects it. Edges taken on the default action are unlabelled.
+use crate::core::{DEFAULT_ACTION, Node};
+use crate::flow::Flow;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+impl Flow {
+    /// Renders the flow as a Graphviz DOT digraph
+    ///
+    /// The start node is drawn in bold and nodes that edges refer to but that are missing from
+    /// the flow are drawn dashed.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph flow {\n    rankdir=LR;\n    node [shape=box];\n");
+        for name in self.node_names() {
+            let label = escape_dot(&self.label(&name, "\n"));
+            let style = match (self.node(&name), self.start_name() == Some(&name)) {
+                (None, _) => ", style=dashed",
+                (Some(_), true) => ", style=bold",
+                (Some(_), false) => "",
+            };
+            let _ = writeln!(
+                dot,
+                "    \"{}\" [label=\"{label}\"{style}];",
+                escape_dot(&name)
+            );
+        }
+        for (from, action, to) in self.all_edges() {
+            let _ = write!(
+                dot,
+                "    \"{}\" -> \"{}\"",
+                escape_dot(from),
+                escape_dot(to)
+            );
+            if action != DEFAULT_ACTION {
+                let _ = write!(dot, " [label=\"{}\"]", escape_dot(action));
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the flow as a Mermaid flowchart
+    ///
+    /// The start node is drawn with a thick border and nodes that edges refer to but that are
+    /// missing from the flow are drawn dashed.
+    pub fn to_mermaid(&self) -> String {
+        let names = self.node_names();
+        let ids: HashMap<&str, String> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), format!("n{i}")))
+            .collect();
+        let mut mermaid = String::from("flowchart LR\n");
+        for name in &names {
+            let label = escape_mermaid(&self.label(name, "<br/>"));
+            let _ = writeln!(mermaid, "    {}[\"{label}\"]", ids[name.as_str()]);
+        }
+        for (from, action, to) in self.all_edges() {
+            let (from, to) = (&ids[from], &ids[to]);
+            if action == DEFAULT_ACTION {
+                let _ = writeln!(mermaid, "    {from} --> {to}");
+            } else {
+                let _ = writeln!(
+                    mermaid,
+                    "    {from} -->|\"{}\"| {to}",
+                    escape_mermaid(action)
+                );
+            }
+        }
+        for name in &names {
+            let id = &ids[name.as_str()];
+            if self.node(name).is_none() {
+                let _ = writeln!(mermaid, "    style {id} stroke-dasharray: 5 5");
+            } else if self.start_name() == Some(name) {
+                let _ = writeln!(mermaid, "    style {id} stroke-width:3px");
+            }
+        }
+        mermaid
+    }
+
+    /// Returns the names of the nodes of the flow in the order added, followed by names only
+    /// referred to by edges
+    fn node_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let nodes = self.nodes().iter().map(|node| node.name());
+        let edges = self
+            .all_edges()
+            .into_iter()
+            .flat_map(|(from, _, to)| [from, to]);
+        for name in nodes.chain(edges) {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    /// Returns the label of the node named `name`, with its lines joined by `separator`
+    fn label(&self, name: &str, separator: &str) -> String {
+        match self.node(name) {
+            Some(node) => node_label(node).join(separator),
+            None => format!("{name}{separator}(missing)"),
+        }
+    }
+}
+
+/// Returns the lines describing a node
+fn node_label(node: &Node) -> Vec<String> {
+    let policy = node.retry_policy();
+    let mut lines = vec![
+        node.name().to_string(),
+        format!("retries: {}", policy.max_retries()),
+        format!("wait: {}", policy.backoff()),
+    ];
+    if let Some(timeout) = node.timeout() {
+        lines.push(format!("timeout: {timeout:?}"));
+    }
+    lines
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A flow starting at a node whose name needs escaping, with a default edge, a labelled edge
+    /// and an edge to a missing node
+    fn flow() -> Flow {
+        let end = Arc::new(Node::new(Some("end")).unwrap());
+        let start = Node::new(Some("say \"hi\""))
+            .unwrap()
+            .with_retries(2)
+            .unwrap()
+            .with_timeout(Duration::from_secs(5))
+            .with_next(end);
+        let mut flow = Flow::new();
+        flow.start(start);
+        flow.connect("end", "yes\\no", "gone");
+        flow
+    }
+
+    #[test]
+    fn renders_dot() {
+        let expected = r#"digraph flow {
+    rankdir=LR;
+    node [shape=box];
+    "say \"hi\"" [label="say \"hi\"\nretries: 2\nwait: 0ns\ntimeout: 5s", style=bold];
+    "end" [label="end\nretries: 0\nwait: 0ns"];
+    "gone" [label="gone\n(missing)", style=dashed];
+    "end" -> "gone" [label="yes\\no"];
+    "say \"hi\"" -> "end";
+}
+"#;
+        assert_eq!(flow().to_dot(), expected);
+    }
+
+    #[test]
+    fn renders_mermaid() {
+        let expected = r#"flowchart LR
+    n0["say #quot;hi#quot;<br/>retries: 2<br/>wait: 0ns<br/>timeout: 5s"]
+    n1["end<br/>retries: 0<br/>wait: 0ns"]
+    n2["gone<br/>(missing)"]
+    n1 -->|"yes\no"| n2
+    n0 --> n1
+    style n0 stroke-width:3px
+    style n2 stroke-dasharray: 5 5
+"#;
+        assert_eq!(flow().to_mermaid(), expected);
+    }
+}
@@ -0,0 +1,661 @@
+//! Flows of connected nodes
+//!
+//! A flow is a graph of nodes identified by name. It starts at a node and follows the edges
+//! between nodes, executing each one in turn. The action returned by a node selects which edge is
+//! followed next. Edges can form cycles, so every run is limited to a maximum number of steps.
+//! Flows containing nodes with async logic are run with [`Flow::run_async`].
+//!
+//! A flow can itself be used as a node of a larger flow through [`SubFlow`].
+//!
+//! Every run reports the tokens used by the model calls of its nodes, including those of sub-flows,
+//! priced with the [`PriceTable`] set by [`Flow::with_prices`]. A failed run reports them in its
+//! [`FlowError`].
+use crate::core::{
+    AsyncNodeLogic, Cancellation, Deadline, ExecContext, Node, NodeError, NodeLogic, NodeOutput,
+};
+use crate::shared::SharedData;
+use crate::usage::{Ledger, PriceTable, UsageReport};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Number of nodes a run may execute unless configured otherwise
+pub const DEFAULT_MAX_STEPS: usize = 1000;
+
+/// A graph of nodes executed from a start node
+///
+/// Nodes are identified by name. Successors set on a node with [`Node::with_successor`] are
+/// added to the flow as edges when the node is added, and further edges, including ones forming
+/// cycles, can be added by name with [`Flow::connect`].
+#[derive(Debug, Clone)]
+pub struct Flow {
+    nodes: Vec<Arc<Node>>,
+    index: HashMap<String, usize>,
+    edges: HashMap<String, HashMap<String, String>>,
+    start: Option<String>,
+    timeout: Option<Duration>,
+    max_steps: usize,
+    max_visits: Option<usize>,
+    prices: PriceTable,
+}
+
+/// The outcome of running a flow
+#[derive(Debug)]
+pub struct FlowResult {
+    /// Output of the last node that was executed
+    pub output: NodeOutput,
+    /// Name of the last node that was executed
+    pub last_node: String,
+    /// Number of nodes executed
+    pub steps: usize,
+    /// The shared data after the run
+    pub shared: SharedData,
+    /// Tokens used and estimated cost of the model calls made during the run
+    pub usage: UsageReport,
+}
+
+/// The error of a failed run, with the usage of the model calls made before it failed
+#[derive(Debug, Error)]
+#[error("{error}")]
+pub struct FlowError {
+    /// The error the run failed with
+    pub error: NodeError,
+    /// Tokens used and estimated cost of the model calls made during the run
+    pub usage: UsageReport,
+}
+
+impl From<FlowError> for NodeError {
+    fn from(err: FlowError) -> Self {
+        err.error
+    }
+}
+
+impl Flow {
+    /// Creates an empty flow
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the node and sets it as the node the flow starts from
+    pub fn start(&mut self, node: impl Into<Arc<Node>>) -> &mut Self {
+        let node = node.into();
+        self.start = Some(node.name().to_string());
+        self.add_node(node)
+    }
+
+    /// Sets the name of the node the flow starts from
+    pub fn start_at(&mut self, name: &str) -> &mut Self {
+        self.start = Some(name.to_string());
+        self
+    }
+
+    /// Adds the node and, recursively, its successors to the flow
+    ///
+    /// A node is registered under its name. When a different node with the same name was added
+    /// before, the first one is kept and the duplicate is ignored when running.
+    pub fn add_node(&mut self, node: impl Into<Arc<Node>>) -> &mut Self {
+        let mut pending = vec![node.into()];
+        while let Some(node) = pending.pop() {
+            let name = node.name().to_string();
+            if let Some(&i) = self.index.get(&name) {
+                if !Arc::ptr_eq(&self.nodes[i], &node) {
+                    tracing::warn!(node = %name, "flow already has a node with this name");
+                    self.nodes.push(node);
+                }
+                continue;
+            }
+            self.index.insert(name.clone(), self.nodes.len());
+            for (action, successor) in node.successors() {
+                self.edges
+                    .entry(name.clone())
+                    .or_default()
+                    .entry(action.clone())
+                    .or_insert_with(|| successor.name().to_string());
+                pending.push(successor.clone());
+            }
+            self.nodes.push(node);
+        }
+        self
+    }
+
+    /// Adds an edge followed when the node named `from` returns `action`
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The name of the node the edge leaves from
+    /// * `action` - The action selecting the edge
+    /// * `to` - The name of the node the edge leads to
+    pub fn connect(&mut self, from: &str, action: &str, to: &str) -> &mut Self {
+        self.edges
+            .entry(from.to_string())
+            .or_default()
+            .insert(action.to_string(), to.to_string());
+        self
+    }
+
+    /// Sets how long a whole run may take before it fails with [`NodeError::Timeout`]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how many nodes a run may execute before it fails with
+    /// [`NodeError::LoopLimitExceeded`]. Defaults to [`DEFAULT_MAX_STEPS`].
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Sets how many times a run may execute the same node before it fails with
+    /// [`NodeError::LoopLimitExceeded`]
+    pub fn with_max_visits(mut self, max_visits: usize) -> Self {
+        self.max_visits = Some(max_visits);
+        self
+    }
+
+    /// Sets the prices used to estimate the cost of the model calls of a run
+    pub fn with_prices(mut self, prices: PriceTable) -> Self {
+        self.prices = prices;
+        self
+    }
+
+    /// Returns the node the flow starts from
+    pub fn start_node(&self) -> Option<&Arc<Node>> {
+        self.start.as_deref().and_then(|name| self.node(name))
+    }
+
+    /// Returns the node registered under `name`
+    pub fn node(&self, name: &str) -> Option<&Arc<Node>> {
+        self.index.get(name).map(|&i| &self.nodes[i])
+    }
+
+    /// Returns every node added to the flow, including ignored duplicates, in the order added
+    pub fn nodes(&self) -> &[Arc<Node>] {
+        &self.nodes
+    }
+
+    /// Returns the edges leaving the node named `from`, as node names keyed by action
+    pub fn edges(&self, from: &str) -> Option<&HashMap<String, String>> {
+        self.edges.get(from)
+    }
+
+    /// Returns every edge of the flow as `(from, action, to)`, sorted by node name then action
+    pub fn all_edges(&self) -> Vec<(&str, &str, &str)> {
+        let mut edges: Vec<_> = self
+            .edges
+            .iter()
+            .flat_map(|(from, actions)| {
+                actions
+                    .iter()
+                    .map(move |(action, to)| (from.as_str(), action.as_str(), to.as_str()))
+            })
+            .collect();
+        edges.sort_unstable();
+        edges
+    }
+
+    /// Returns the name of the node the flow starts from
+    pub fn start_name(&self) -> Option<&str> {
+        self.start.as_deref()
+    }
+
+    /// Returns how long a whole run may take, if limited
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns how many nodes a run may execute
+    pub fn max_steps(&self) -> usize {
+        self.max_steps
+    }
+
+    /// Returns how many times a run may execute the same node, if limited
+    pub fn max_visits(&self) -> Option<usize> {
+        self.max_visits
+    }
+
+    /// Returns the prices used to estimate the cost of the model calls of a run
+    pub fn prices(&self) -> &PriceTable {
+        &self.prices
+    }
+
+    /// Returns true if any node of the flow has async logic
+    pub fn is_async(&self) -> bool {
+        self.nodes.iter().any(|node| node.is_async())
+    }
+
+    /// Wraps the flow in a node with the given name that shares the parent flow's data
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        SubFlow::new(self).into_node(name)
+    }
+
+    /// Runs the flow from the start node until a node has no edge for its action
+    ///
+    /// # Arguments
+    ///
+    /// * `shared` - The data made available to every node of the flow
+    pub fn run(&self, shared: impl Into<SharedData>) -> Result<FlowResult, FlowError> {
+        self.run_within(shared.into(), &Cancellation::default(), &Ledger::default())
+    }
+
+    /// Runs the flow like [`Flow::run`], executing each node with [`Node::exec_async`]
+    pub async fn run_async(&self, shared: impl Into<SharedData>) -> Result<FlowResult, FlowError> {
+        self.run_within_async(shared.into(), &Cancellation::default(), &Ledger::default())
+            .await
+    }
+
+    /// Runs the flow, stopping before the next node once `cancellation` is cancelled
+    ///
+    /// Model calls are recorded in a ledger of the run nested in `parent`.
+    fn run_within(
+        &self,
+        shared: SharedData,
+        cancellation: &Cancellation,
+        parent: &Ledger,
+    ) -> Result<FlowResult, FlowError> {
+        let usage = parent.child();
+        let walked = self.walk(&shared, cancellation, &usage);
+        self.finish(walked, shared, &usage)
+    }
+
+    /// Async counterpart of [`Flow::run_within`]
+    async fn run_within_async(
+        &self,
+        shared: SharedData,
+        cancellation: &Cancellation,
+        parent: &Ledger,
+    ) -> Result<FlowResult, FlowError> {
+        let usage = parent.child();
+        let walked = self.walk_async(&shared, cancellation, &usage).await;
+        self.finish(walked, shared, &usage)
+    }
+
+    /// Executes the nodes from the start node, returning the last output, node and step count
+    fn walk(
+        &self,
+        shared: &SharedData,
+        cancellation: &Cancellation,
+        usage: &Ledger,
+    ) -> Result<(NodeOutput, String, usize), NodeError> {
+        let deadline = self.timeout.map(Deadline::after);
+        let mut walk = Walk::new(self, cancellation);
+        let mut current = walk.enter(self.start.as_deref())?;
+        loop {
+            let output = current.run(shared, deadline.as_ref(), cancellation, usage)?;
+            match self.next_node(&current, &output) {
+                Some(next) => current = walk.enter(Some(next))?,
+                None => return Ok((output, current.name().to_string(), walk.steps)),
+            }
+        }
+    }
+
+    /// Async counterpart of [`Flow::walk`]
+    async fn walk_async(
+        &self,
+        shared: &SharedData,
+        cancellation: &Cancellation,
+        usage: &Ledger,
+    ) -> Result<(NodeOutput, String, usize), NodeError> {
+        let deadline = self.timeout.map(Deadline::after);
+        let mut walk = Walk::new(self, cancellation);
+        let mut current = walk.enter(self.start.as_deref())?;
+        loop {
+            let output = current
+                .run_async(shared, deadline.as_ref(), cancellation, usage)
+                .await?;
+            match self.next_node(&current, &output) {
+                Some(next) => current = walk.enter(Some(next))?,
+                None => return Ok((output, current.name().to_string(), walk.steps)),
+            }
+        }
+    }
+
+    /// Turns the outcome of a walk into the result of the run, with its usage either way
+    fn finish(
+        &self,
+        walked: Result<(NodeOutput, String, usize), NodeError>,
+        shared: SharedData,
+        usage: &Ledger,
+    ) -> Result<FlowResult, FlowError> {
+        let usage = usage.report(&self.prices);
+        match walked {
+            Ok((output, last_node, steps)) => Ok(FlowResult {
+                output,
+                last_node,
+                steps,
+                shared,
+                usage,
+            }),
+            Err(error) => Err(FlowError { error, usage }),
+        }
+    }
+
+    /// Returns the name of the node following `node` for `output`, if any
+    fn next_node(&self, node: &Node, output: &NodeOutput) -> Option<&str> {
+        let edges = self.edges.get(node.name())?;
+        let next = edges.get(output.action()).map(String::as_str);
+        if next.is_none() {
+            tracing::warn!(
+                node = node.name(),
+                action = output.action(),
+                "no successor for action, ending flow"
+            );
+        }
+        next
+    }
+}
+
+impl Default for Flow {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            edges: HashMap::new(),
+            start: None,
+            timeout: None,
+            max_steps: DEFAULT_MAX_STEPS,
+            max_visits: None,
+            prices: PriceTable::default(),
+        }
+    }
+}
+
+/// Tracks the nodes visited by a run to enforce the loop limits
+struct Walk<'a> {
+    flow: &'a Flow,
+    cancellation: &'a Cancellation,
+    steps: usize,
+    visits: HashMap<&'a str, usize>,
+}
+
+impl<'a> Walk<'a> {
+    fn new(flow: &'a Flow, cancellation: &'a Cancellation) -> Self {
+        Self {
+            flow,
+            cancellation,
+            steps: 0,
+            visits: HashMap::new(),
+        }
+    }
+
+    /// Looks up the node to execute next and counts the visit
+    fn enter(&mut self, name: Option<&'a str>) -> Result<Arc<Node>, NodeError> {
+        let name = name.ok_or(NodeError::MissingStartNode)?;
+        if self.cancellation.is_cancelled() {
+            return Err(NodeError::Cancelled(name.to_string()));
+        }
+        let node = self
+            .flow
+            .node(name)
+            .ok_or_else(|| NodeError::UnknownNode(name.to_string()))?;
+        if self.steps >= self.flow.max_steps {
+            return Err(NodeError::LoopLimitExceeded {
+                node: name.to_string(),
+                limit: self.flow.max_steps,
+            });
+        }
+        let visits = self.visits.entry(name).or_default();
+        if let Some(max_visits) = self.flow.max_visits
+            && *visits >= max_visits
+        {
+            return Err(NodeError::LoopLimitExceeded {
+                node: name.to_string(),
+                limit: max_visits,
+            });
+        }
+        *visits += 1;
+        self.steps += 1;
+        Ok(node.clone())
+    }
+}
+
+/// How a sub-flow sees the data of the flow running it
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Scope {
+    Shared,
+    Isolated { outputs: Vec<String> },
+}
+
+/// Logic running a whole flow as a single node
+///
+/// The action the sub-flow ends with becomes the action of the node, so the parent flow can
+/// branch on it.
+#[derive(Debug, Clone)]
+pub struct SubFlow {
+    flow: Flow,
+    scope: Scope,
+}
+
+impl SubFlow {
+    /// Creates logic running `flow` on the parent flow's shared data
+    pub fn new(flow: Flow) -> Self {
+        Self {
+            flow,
+            scope: Scope::Shared,
+        }
+    }
+
+    /// Runs the sub-flow on a copy of the parent's shared data
+    ///
+    /// Only the entries stored under `outputs` are copied back to the parent once it finishes.
+    pub fn isolated(mut self, outputs: &[&str]) -> Self {
+        self.scope = Scope::Isolated {
+            outputs: outputs.iter().map(|key| key.to_string()).collect(),
+        };
+        self
+    }
+
+    /// Creates a node with the given name running this sub-flow
+    ///
+    /// The node has async logic if any node of the sub-flow does.
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        let node = Node::new(Some(name))?;
+        Ok(if self.flow.is_async() {
+            node.with_async_logic(self)
+        } else {
+            node.with_logic(self)
+        })
+    }
+
+    /// Returns the data the sub-flow runs on
+    fn state(&self, parent: &SharedData) -> SharedData {
+        match self.scope {
+            Scope::Shared => parent.clone(),
+            Scope::Isolated { .. } => parent.fork(),
+        }
+    }
+
+    /// Copies the outputs of an isolated run back to the parent and returns the final action
+    fn finish(&self, parent: &SharedData, result: FlowResult) -> Value {
+        if let Scope::Isolated { outputs } = &self.scope {
+            for key in outputs {
+                match result.shared.get_value(key) {
+                    Some(value) => parent.set_value(key.clone(), value),
+                    None => parent.remove(key),
+                };
+            }
+        }
+        Value::from(result.output.action())
+    }
+}
+
+/// Turns the final action of a sub-flow back into a node output
+fn sub_flow_output(exec_res: Value) -> NodeOutput {
+    match exec_res {
+        Value::String(action) => NodeOutput::Action(action),
+        _ => NodeOutput::default(),
+    }
+}
+
+impl NodeLogic for SubFlow {
+    fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let result =
+            self.flow
+                .run_within(self.state(ctx.shared()), ctx.cancellation(), ctx.usage())?;
+        Ok(self.finish(ctx.shared(), result))
+    }
+
+    fn post(
+        &self,
+        _shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        Ok(sub_flow_output(exec_res))
+    }
+}
+
+#[async_trait]
+impl AsyncNodeLogic for SubFlow {
+    async fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let result = self
+            .flow
+            .run_within_async(self.state(ctx.shared()), ctx.cancellation(), ctx.usage())
+            .await?;
+        Ok(self.finish(ctx.shared(), result))
+    }
+
+    async fn post(
+        &self,
+        _shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        Ok(sub_flow_output(exec_res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts its executions under "count", returning "again" until the count reaches `until`
+    /// and "done" after
+    struct Count {
+        until: Option<usize>,
+    }
+
+    impl NodeLogic for Count {
+        fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+            let count = ctx.shared().get::<usize>("count")?.unwrap_or_default() + 1;
+            ctx.shared().set("count", count)?;
+            Ok(Value::from(count))
+        }
+
+        fn post(
+            &self,
+            _shared: &SharedData,
+            _prep_res: Value,
+            exec_res: Value,
+        ) -> Result<NodeOutput, NodeError> {
+            Ok(match self.until {
+                Some(until) if exec_res.as_u64() >= Some(until as u64) => {
+                    NodeOutput::Action("done".to_string())
+                }
+                Some(_) => NodeOutput::Action("again".to_string()),
+                None => NodeOutput::default(),
+            })
+        }
+    }
+
+    fn count(name: &str, until: Option<usize>) -> Node {
+        Node::new(Some(name)).unwrap().with_logic(Count { until })
+    }
+
+    #[test]
+    fn self_loops_stop_at_max_visits() {
+        let mut flow = Flow::new().with_max_visits(3);
+        flow.start(count("loop", None));
+        flow.connect("loop", "default", "loop");
+        let shared = SharedData::new();
+        let err = flow.run(shared.clone()).unwrap_err();
+
+        assert!(matches!(
+            err.error,
+            NodeError::LoopLimitExceeded { node, limit: 3 } if node == "loop"
+        ));
+        assert_eq!(shared.get_value("count"), Some(Value::from(3)));
+    }
+
+    #[test]
+    fn cycles_stop_at_max_steps() {
+        let mut flow = Flow::new().with_max_steps(5);
+        flow.start(count("a", None));
+        flow.add_node(count("b", None)).add_node(count("c", None));
+        flow.connect("a", "default", "b")
+            .connect("b", "default", "c")
+            .connect("c", "default", "a");
+        let shared = SharedData::new();
+        let err = flow.run(shared.clone()).unwrap_err();
+
+        assert!(matches!(
+            err.error,
+            NodeError::LoopLimitExceeded { node, limit: 5 } if node == "c"
+        ));
+        assert_eq!(shared.get_value("count"), Some(Value::from(5)));
+    }
+
+    #[test]
+    fn cycles_exit_on_another_action() {
+        let mut flow = Flow::new().with_max_visits(3);
+        flow.start(count("loop", Some(3)));
+        flow.add_node(count("finish", None));
+        flow.connect("loop", "again", "loop")
+            .connect("loop", "done", "finish");
+        let result = flow.run(SharedData::new()).unwrap();
+
+        assert_eq!(result.last_node, "finish");
+        assert_eq!(result.steps, 4);
+        assert_eq!(result.shared.get_value("count"), Some(Value::from(4)));
+    }
+
+    /// Approves amounts up to 100 and rejects larger ones
+    struct Review;
+
+    impl NodeLogic for Review {
+        fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+            shared.require("amount")
+        }
+
+        fn exec(&self, prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+            Ok(Value::from(prep_res.as_u64() <= Some(100)))
+        }
+
+        fn post(
+            &self,
+            _shared: &SharedData,
+            _prep_res: Value,
+            exec_res: Value,
+        ) -> Result<NodeOutput, NodeError> {
+            let action = if exec_res == Value::Bool(true) {
+                "approve"
+            } else {
+                "reject"
+            };
+            Ok(NodeOutput::Action(action.to_string()))
+        }
+    }
+
+    #[test]
+    fn follows_the_successor_of_the_returned_action() {
+        let review = Node::new(Some("review"))
+            .unwrap()
+            .with_logic(Review)
+            .with_successor("approve", Arc::new(count("pay", None)))
+            .with_successor("reject", Arc::new(count("notify", None)));
+        let mut flow = Flow::new();
+        flow.start(review);
+
+        for (amount, expected) in [(50, "pay"), (500, "notify")] {
+            let shared = SharedData::new();
+            shared.set("amount", amount).unwrap();
+            let result = flow.run(shared).unwrap();
+            assert_eq!(result.last_node, expected);
+            assert_eq!(result.steps, 2);
+            assert_eq!(result.shared.get_value("count"), Some(Value::from(1)));
+        }
+    }
+}
@@ -15,13 +15,28 @@
 //!
 //! ```rust
 //! use llmflow::prelude::*;
+//! use serde_json::Value;
 //! use std::collections::HashMap;
 //!
 //! struct MyNode;
 //!
-//! impl Node for MyNode {
-//!     fn exec(&self, prep_res: SharedData) -> Result<NodeOutput, NodeError> {
+//! impl NodeLogic for MyNode {
+//!     fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+//!         shared.require("key")
+//!     }
+//!
+//!     fn exec(&self, prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
 //!         println!("Processing data: {:?}", prep_res);
+//!         Ok(Value::from("processed"))
+//!     }
+//!
+//!     fn post(
+//!         &self,
+//!         shared: &SharedData,
+//!         _prep_res: Value,
+//!         exec_res: Value,
+//!     ) -> Result<NodeOutput, NodeError> {
+//!         shared.set_value("result", exec_res);
 //!         Ok(NodeOutput::Action("default".to_string()))
 //!     }
 //! }
@@ -29,7 +44,7 @@
 //! #[tokio::main]
 //! async fn main() {
 //!     let mut flow = Flow::new();
-//!     let node = Box::new(MyNode);
+//!     let node = Node::new(Some("my_node")).unwrap().with_logic(MyNode);
 //!
 //!     flow.start(node);
 //!
@@ -41,9 +56,32 @@
 //! }
 //! ```
 
+pub mod batch;
 pub mod core;
+pub mod definition;
+pub mod export;
+pub mod flow;
+pub mod llm;
+pub mod prompt;
+pub mod retry;
+pub mod shared;
+pub mod usage;
+pub mod validation;
 
 /// Re-export of the most commonly used types and traits
 pub mod prelude {
-    pub use crate::core::Node;
+    pub use crate::batch::{BatchMode, BatchNode};
+    pub use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError, NodeLogic, NodeOutput};
+    pub use crate::definition::{FlowDefinition, NodeDefinition, NodeRegistry};
+    pub use crate::flow::{Flow, FlowError, FlowResult, SubFlow};
+    pub use crate::llm::{
+        AgentNode, ConversationMemory, LlmClient, LlmNode, MemoryLimit, MemoryNode, StreamEvent,
+        StructuredNode, Tool, ToolNode, ToolRegistry,
+    };
+    pub use crate::prompt::PromptTemplate;
+    pub use crate::retry::{Backoff, RetryPolicy};
+    pub use crate::shared::SharedData;
+    pub use crate::usage::{ModelPrice, PriceTable, UsageReport};
+    pub use crate::validation::{ValidationIssue, ValidationReport};
+    pub use async_trait::async_trait;
 }
@@ -0,0 +1,279 @@
+use super::node::to_value;
+use super::tool::{TOOL_CALLS_ACTION, ToolNode};
+use super::{Message, Role};
+use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError};
+use crate::retry::RetryPolicy;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+/// One round of an agent: its reasoning, the tools it called and what they returned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentStep {
+    /// Number of the step, starting at 1
+    pub step: usize,
+    /// Text the model produced alongside its tool calls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thought: Option<String>,
+    /// Tools called in this step
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<AgentAction>,
+    /// The final answer, set on the last step
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+}
+
+/// A tool call made by an agent and its result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentAction {
+    pub tool: String,
+    pub arguments: Value,
+    pub observation: String,
+}
+
+/// Logic running a ReAct-style agent to completion
+///
+/// Every step asks the model of the wrapped [`ToolNode`] for the next move and runs the tools it
+/// calls, until the model replies without calling tools or `max_steps` steps have run, which
+/// fails with [`NodeError::LoopLimitExceeded`]. The answer is stored under the output key of the
+/// tool node's [`LlmNode`](super::LlmNode).
+///
+/// Each step runs as a node of its own, so failed model calls are retried according to
+/// [`with_retry_policy`](AgentNode::with_retry_policy) without restarting the agent. Every
+/// finished step is appended to the list of [`AgentStep`]s under the steps key, where it can be
+/// watched while the agent runs. Each execution starts a new conversation.
+#[derive(Clone)]
+pub struct AgentNode {
+    tools: ToolNode,
+    steps_key: String,
+    max_steps: usize,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+}
+
+impl AgentNode {
+    /// Creates logic running the agent described by `tools`
+    ///
+    /// # Arguments
+    ///
+    /// * `tools` - The model, its prompt and the tools it may call
+    /// * `steps_key` - The shared data key the steps are recorded under
+    /// * `max_steps` - The max number of steps before the agent gives up
+    pub fn new(tools: ToolNode, steps_key: &str, max_steps: usize) -> Self {
+        Self {
+            tools,
+            steps_key: steps_key.to_string(),
+            max_steps,
+            retry: RetryPolicy::default(),
+            timeout: None,
+        }
+    }
+
+    /// Sets how a failed step is retried
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Sets how long a single attempt of a step may run
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the max number of steps
+    pub fn max_steps(&self) -> usize {
+        self.max_steps
+    }
+
+    /// Creates a node with the given name running this logic
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?.with_async_logic(self))
+    }
+
+    /// Creates the node running a single step
+    fn step_node(&self, name: &str) -> Result<Node, NodeError> {
+        let node = Node::new(Some(name))?
+            .with_async_logic(self.tools.clone())
+            .with_retry_policy(self.retry.clone());
+        Ok(match self.timeout {
+            Some(timeout) => node.with_timeout(timeout),
+            None => node,
+        })
+    }
+}
+
+/// Describes the last exchange of `conversation` as a step
+fn record(step: usize, conversation: &[Message]) -> AgentStep {
+    let reply = conversation
+        .iter()
+        .rposition(|m| m.role == Role::Assistant)
+        .unwrap_or_default();
+    let message = &conversation[reply];
+    let results = &conversation[reply + 1..];
+    let actions: Vec<AgentAction> = message
+        .tool_calls
+        .iter()
+        .map(|call| AgentAction {
+            tool: call.name.clone(),
+            arguments: call.arguments.clone(),
+            observation: results
+                .iter()
+                .find(|m| m.tool_call_id.as_deref() == Some(&call.id))
+                .map(|m| m.content.clone())
+                .unwrap_or_default(),
+        })
+        .collect();
+    let text = Some(message.content.clone()).filter(|text| !text.is_empty());
+    if actions.is_empty() {
+        AgentStep {
+            step,
+            thought: None,
+            actions,
+            answer: Some(text.unwrap_or_default()),
+        }
+    } else {
+        AgentStep {
+            step,
+            thought: text,
+            actions,
+            answer: None,
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncNodeLogic for AgentNode {
+    async fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let shared = ctx.shared();
+        let conversation_key = self.tools.conversation_key();
+        shared.remove(conversation_key);
+        shared.set(self.steps_key.clone(), Vec::<AgentStep>::new())?;
+
+        let step_node = self.step_node(ctx.node_name())?;
+        for step in 1..=self.max_steps {
+            let output = step_node
+                .run_async(shared, None, ctx.cancellation(), ctx.usage())
+                .await?;
+            let conversation: Vec<Message> = shared.require(conversation_key)?;
+            let record = record(step, &conversation);
+            let answer = record.answer.clone();
+            let record = to_value(&record)?;
+            shared.update(|values| {
+                if let Some(Value::Array(steps)) = values.get_mut(&self.steps_key) {
+                    steps.push(record);
+                }
+            });
+            if output.action() != TOOL_CALLS_ACTION {
+                return Ok(answer.into());
+            }
+        }
+        Err(NodeError::LoopLimitExceeded {
+            node: ctx.node_name().to_string(),
+            limit: self.max_steps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::scripted::{Scripted, calling};
+    use crate::llm::{ChatResponse, LlmNode, Tool, ToolRegistry};
+    use crate::shared::SharedData;
+    use serde_json::json;
+
+    /// Returns the text it is called with
+    struct Echo;
+
+    #[async_trait]
+    impl Tool for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn description(&self) -> &str {
+            "Returns the text"
+        }
+
+        fn parameters(&self) -> Value {
+            json!({ "type": "object", "properties": { "text": { "type": "string" } } })
+        }
+
+        async fn invoke(&self, arguments: Value) -> Result<Value, NodeError> {
+            Ok(arguments["text"].clone())
+        }
+    }
+
+    fn agent(replies: Vec<ChatResponse>, max_steps: usize) -> (Node, SharedData) {
+        let mut tools = ToolRegistry::new();
+        tools.register(Echo);
+        let llm = LlmNode::new(Scripted::new(replies), "task", "answer");
+        let node = AgentNode::new(ToolNode::new(llm, tools, "chat"), "steps", max_steps)
+            .into_node("agent")
+            .unwrap();
+        let shared = SharedData::new();
+        shared.set("task", "Say hi").unwrap();
+        (node, shared)
+    }
+
+    fn echo(text: &str) -> ChatResponse {
+        calling("echo", json!({ "text": text }))
+    }
+
+    #[tokio::test]
+    async fn ends_on_a_text_reply() {
+        let (node, shared) = agent(vec![echo("hi"), ChatResponse::new("hi")], 5);
+        node.exec_async(&shared).await.unwrap();
+
+        assert_eq!(shared.get_value("answer"), Some(json!("hi")));
+        assert_eq!(shared.require::<Vec<AgentStep>>("steps").unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_after_max_steps() {
+        let (node, shared) = agent(vec![echo("a"), echo("b"), echo("c")], 2);
+        let err = node.exec_async(&shared).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            NodeError::LoopLimitExceeded { node, limit: 2 } if node == "agent"
+        ));
+        assert!(!shared.contains_key("answer"));
+        assert_eq!(shared.require::<Vec<AgentStep>>("steps").unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn records_every_step() {
+        let thinking = ChatResponse {
+            content: "I should echo".to_string(),
+            ..echo("hi")
+        };
+        let (node, shared) = agent(vec![thinking, ChatResponse::new("hi")], 5);
+        node.exec_async(&shared).await.unwrap();
+
+        let steps: Vec<AgentStep> = shared.require("steps").unwrap();
+        assert_eq!(
+            steps,
+            [
+                AgentStep {
+                    step: 1,
+                    thought: Some("I should echo".to_string()),
+                    actions: vec![AgentAction {
+                        tool: "echo".to_string(),
+                        arguments: json!({ "text": "hi" }),
+                        observation: "hi".to_string(),
+                    }],
+                    answer: None,
+                },
+                AgentStep {
+                    step: 2,
+                    thought: None,
+                    actions: Vec::new(),
+                    answer: Some("hi".to_string()),
+                },
+            ]
+        );
+    }
+}
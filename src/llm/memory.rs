@@ -0,0 +1,313 @@
+use super::node::{from_value, record_usage, to_value};
+use super::{ChatRequest, HeuristicTokenizer, LlmClient, Message, Role, Tokenizer};
+use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError, NodeOutput};
+use crate::shared::SharedData;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Instruction given to the summarizer
+pub const SUMMARY_PROMPT: &str = "Summarize the conversation below in a few sentences. Keep \
+    facts, decisions and open questions. If an earlier summary is given, fold it into the new one.";
+
+/// The chat history of a multi-turn conversation, kept in the shared data
+///
+/// Older turns can be dropped with [`trim`](ConversationMemory::trim) or folded into a summary
+/// by a [`MemoryNode`]. The summary is sent to the model ahead of the remaining messages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConversationMemory {
+    /// Summary of the turns dropped so far
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// The turns kept verbatim, oldest first
+    #[serde(default)]
+    pub messages: Vec<Message>,
+}
+
+/// How much history a [`ConversationMemory`] keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryLimit {
+    max_messages: Option<usize>,
+    max_tokens: Option<usize>,
+}
+
+impl ConversationMemory {
+    /// Creates an empty memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the memory stored under `key`, or an empty memory if there is none
+    pub fn load(shared: &SharedData, key: &str) -> Result<Self, NodeError> {
+        Ok(shared.get(key)?.unwrap_or_default())
+    }
+
+    /// Stores the memory under `key`
+    pub fn save(&self, shared: &SharedData, key: &str) -> Result<(), NodeError> {
+        shared.set(key, self)
+    }
+
+    /// Appends a message
+    pub fn push(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+
+    /// Returns the messages to send to the model, starting with the summary if there is one
+    pub fn history(&self) -> Vec<Message> {
+        let summary = self.summary.iter().map(|summary| {
+            Message::system(format!("Summary of the earlier conversation:\n{summary}"))
+        });
+        summary.chain(self.messages.iter().cloned()).collect()
+    }
+
+    /// Returns the estimated number of tokens of the kept messages
+    pub fn estimated_tokens(&self) -> usize {
+        self.messages.iter().map(estimate_tokens).sum()
+    }
+
+    /// Drops the oldest messages until the memory is within `limit`, returning them
+    ///
+    /// Tool results are dropped together with the call they answer.
+    pub fn trim(&mut self, limit: &MemoryLimit) -> Vec<Message> {
+        let len = self.messages.len();
+        let mut drop = limit.max_messages.map_or(0, |max| len.saturating_sub(max));
+        if let Some(max) = limit.max_tokens {
+            let mut total: usize = self.messages[drop..].iter().map(estimate_tokens).sum();
+            while total > max && drop < len {
+                total -= estimate_tokens(&self.messages[drop]);
+                drop += 1;
+            }
+        }
+        while drop < len && self.messages[drop].role == Role::Tool {
+            drop += 1;
+        }
+        self.messages.drain(..drop).collect()
+    }
+}
+
+impl MemoryLimit {
+    /// Creates a limit keeping every message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps at most `max_messages` messages
+    pub fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = Some(max_messages);
+        self
+    }
+
+    /// Keeps messages up to an estimated `max_tokens` tokens
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Returns the max number of messages kept, if limited
+    pub fn max_messages(&self) -> Option<usize> {
+        self.max_messages
+    }
+
+    /// Returns the max estimated number of tokens kept, if limited
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+}
+
+/// Estimates the tokens of a message with the [`HeuristicTokenizer`]
+fn estimate_tokens(message: &Message) -> usize {
+    HeuristicTokenizer.count_message(message)
+}
+
+/// Logic keeping a [`ConversationMemory`] within a [`MemoryLimit`]
+///
+/// Run after the nodes adding to the memory. Without a summarizer the dropped turns are
+/// forgotten; with one they are folded into the memory's summary by asking the model, so a
+/// failed summary is retried like any other node. The tokens used by the summarizer are recorded
+/// like those of an [`LlmNode`](super::LlmNode).
+#[derive(Clone)]
+pub struct MemoryNode {
+    memory_key: String,
+    limit: MemoryLimit,
+    summarizer: Option<Arc<dyn LlmClient>>,
+}
+
+impl MemoryNode {
+    /// Creates logic trimming the memory stored under `memory_key` to `limit`
+    pub fn new(memory_key: &str, limit: MemoryLimit) -> Self {
+        Self {
+            memory_key: memory_key.to_string(),
+            limit,
+            summarizer: None,
+        }
+    }
+
+    /// Summarizes dropped turns with `client` instead of forgetting them
+    pub fn with_summarizer(mut self, client: Arc<dyn LlmClient>) -> Self {
+        self.summarizer = Some(client);
+        self
+    }
+
+    /// Creates a node with the given name running this logic
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?.with_async_logic(self))
+    }
+}
+
+/// Renders messages as a plain transcript for the summarizer
+fn transcript(messages: &[Message]) -> String {
+    let lines = messages.iter().map(|message| {
+        let role = match message.role {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        };
+        let calls = message
+            .tool_calls
+            .iter()
+            .map(|call| format!(" [calls {}({})]", call.name, call.arguments));
+        format!("{role}: {}{}", message.content, calls.collect::<String>())
+    });
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+#[async_trait]
+impl AsyncNodeLogic for MemoryNode {
+    async fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        to_value(&ConversationMemory::load(shared, &self.memory_key)?)
+    }
+
+    async fn exec(&self, prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let mut memory: ConversationMemory = from_value(prep_res)?;
+        let dropped = memory.trim(&self.limit);
+        if let Some(client) = self.summarizer.as_ref().filter(|_| !dropped.is_empty()) {
+            let mut text = String::new();
+            if let Some(summary) = &memory.summary {
+                text = format!("Earlier summary:\n{summary}\n\n");
+            }
+            text.push_str(&format!("Conversation:\n{}", transcript(&dropped)));
+            let request =
+                ChatRequest::new(vec![Message::system(SUMMARY_PROMPT), Message::user(text)]);
+            let response = client.chat(&request).await?;
+            record_usage(ctx, &request, &response, &HeuristicTokenizer);
+            memory.summary = Some(response.content);
+        }
+        to_value(&memory)
+    }
+
+    async fn post(
+        &self,
+        shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        shared.set_value(self.memory_key.clone(), exec_res);
+        Ok(NodeOutput::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ChatResponse;
+    use crate::llm::scripted::{Scripted, calling};
+    use serde_json::json;
+
+    fn memory(messages: Vec<Message>) -> ConversationMemory {
+        ConversationMemory {
+            summary: None,
+            messages,
+        }
+    }
+
+    fn turns() -> Vec<Message> {
+        vec![
+            Message::user("u1"),
+            Message::assistant("a1"),
+            Message::user("u2"),
+            Message::assistant("a2"),
+        ]
+    }
+
+    #[test]
+    fn trims_to_max_messages() {
+        let mut memory = memory(turns());
+        let dropped = memory.trim(&MemoryLimit::new().with_max_messages(3));
+        assert_eq!(dropped, turns()[..1]);
+        assert_eq!(memory.messages, turns()[1..]);
+
+        assert!(memory.trim(&MemoryLimit::new()).is_empty());
+    }
+
+    #[test]
+    fn trims_to_max_tokens() {
+        let long = Message::user("a much longer message than the others in this conversation");
+        let mut memory = memory([vec![long.clone()], turns()].concat());
+        let max_tokens = turns().iter().map(estimate_tokens).sum();
+        let dropped = memory.trim(&MemoryLimit::new().with_max_tokens(max_tokens));
+        assert_eq!(dropped, [long]);
+        assert_eq!(memory.messages, turns());
+        assert!(memory.estimated_tokens() <= max_tokens);
+    }
+
+    #[test]
+    fn drops_tool_results_with_their_call() {
+        let call = calling("search", json!({ "q": "rust" })).tool_calls;
+        let messages = vec![
+            Message::user("u1"),
+            Message::assistant("").with_tool_calls(call),
+            Message::tool("call_search", "first"),
+            Message::tool("call_search", "second"),
+            Message::assistant("a1"),
+        ];
+        let mut memory = memory(messages.clone());
+        let dropped = memory.trim(&MemoryLimit::new().with_max_messages(3));
+        assert_eq!(dropped, messages[..4]);
+        assert_eq!(memory.messages, messages[4..]);
+    }
+
+    #[tokio::test]
+    async fn summarizes_dropped_turns_into_the_summary() {
+        let client = Scripted::new([ChatResponse::new("new summary")]);
+        let node = MemoryNode::new("memory", MemoryLimit::new().with_max_messages(2))
+            .with_summarizer(client.clone())
+            .into_node("memory")
+            .unwrap();
+        let shared = SharedData::new();
+        let memory = ConversationMemory {
+            summary: Some("old summary".to_string()),
+            messages: turns(),
+        };
+        memory.save(&shared, "memory").unwrap();
+        node.exec_async(&shared).await.unwrap();
+
+        let memory = ConversationMemory::load(&shared, "memory").unwrap();
+        assert_eq!(memory.summary.as_deref(), Some("new summary"));
+        assert_eq!(memory.messages, turns()[2..]);
+        let request = &client.requests()[0];
+        assert_eq!(request.messages[0].content, SUMMARY_PROMPT);
+        assert_eq!(
+            request.messages[1].content,
+            "Earlier summary:\nold summary\n\nConversation:\nuser: u1\nassistant: a1"
+        );
+
+        node.exec_async(&shared).await.unwrap();
+        assert_eq!(client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn forgets_dropped_turns_without_a_summarizer() {
+        let node = MemoryNode::new("memory", MemoryLimit::new().with_max_messages(2))
+            .into_node("memory")
+            .unwrap();
+        let shared = SharedData::new();
+        memory(turns()).save(&shared, "memory").unwrap();
+        node.exec_async(&shared).await.unwrap();
+
+        let memory = ConversationMemory::load(&shared, "memory").unwrap();
+        assert_eq!(memory.summary, None);
+        assert_eq!(memory.messages, turns()[2..]);
+    }
+}
@@ -0,0 +1,284 @@
+//! Language model integration
+//!
+//! [`LlmClient`] abstracts over chat completion providers, and [`LlmNode`] runs a chat completion
+//! as a node, so calls to the model are retried, timed out and branched on like any other node.
+//! With the `openai` feature (enabled by default), [`OpenAiClient`] talks to any server speaking
+//! the OpenAI chat completions protocol. [`StructuredNode`] asks the model for a typed value
+//! instead of free text, and [`ToolNode`] lets it call the [`Tool`]s of a [`ToolRegistry`].
+//! [`AgentNode`] runs a tool-calling agent until it answers. A [`ConversationMemory`] carries
+//! the chat history of multi-turn conversations between runs. A [`ContextBudget`] checks that
+//! requests fit into the model's context window, counting with a [`Tokenizer`].
+//!
+//! ```rust
+//! use llmflow::llm::{ChatRequest, ChatResponse, LlmClient, LlmNode};
+//! use llmflow::prelude::*;
+//! use std::sync::Arc;
+//!
+//! struct Echo;
+//!
+//! #[async_trait]
+//! impl LlmClient for Echo {
+//!     async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, NodeError> {
+//!         let last = request.messages.last().map(|m| m.content.clone());
+//!         Ok(ChatResponse::new(last.unwrap_or_default()))
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), NodeError> {
+//! let node = LlmNode::new(Arc::new(Echo), "question", "answer")
+//!     .with_system("Answer briefly.")
+//!     .with_temperature(0.2)
+//!     .into_node("ask")?
+//!     .with_retries(3)?;
+//!
+//! let shared = SharedData::new();
+//! shared.set("question", "What is a flow?")?;
+//! node.exec_async(&shared).await?;
+//! assert_eq!(shared.require::<String>("answer")?, "What is a flow?");
+//! # Ok(())
+//! # }
+//! ```
+mod agent;
+mod memory;
+mod node;
+#[cfg(feature = "openai")]
+mod openai;
+mod schema;
+#[cfg(test)]
+mod scripted;
+mod structured;
+mod tokenizer;
+mod tool;
+
+pub use agent::{AgentAction, AgentNode, AgentStep};
+pub use memory::{ConversationMemory, MemoryLimit, MemoryNode, SUMMARY_PROMPT};
+pub use node::LlmNode;
+#[cfg(feature = "openai")]
+pub use openai::{OPENAI_BASE_URL, OpenAiClient};
+pub use structured::StructuredNode;
+pub use tokenizer::{BpeTokenizer, ContextBudget, HeuristicTokenizer, Tokenizer};
+pub use tool::{TOOL_CALLS_ACTION, Tool, ToolNode, ToolRegistry};
+
+use crate::core::NodeError;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The author of a chat message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+/// A message of a chat conversation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    /// Tools the assistant asked to call
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a tool message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    /// Creates a message with the given role
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// Creates a system message
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    /// Creates a user message
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    /// Creates an assistant message
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+
+    /// Creates a message holding the result of the tool call with the given id
+    pub fn tool(call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+
+    /// Sets the tools the assistant asked to call
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+}
+
+/// A request of the model to call a tool
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Id the result of the call is reported under
+    pub id: String,
+    /// Name of the tool
+    pub name: String,
+    /// Arguments of the call, which should match the tool's parameters
+    pub arguments: Value,
+}
+
+/// A tool as described to the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: Value,
+}
+
+/// A request for a chat completion
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatRequest {
+    /// Model to use instead of the client's default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The conversation so far
+    pub messages: Vec<Message>,
+    /// Sampling temperature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Max number of tokens to generate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Sequences at which generation stops
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// Tools the model may call
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+}
+
+impl ChatRequest {
+    /// Creates a request for the given conversation
+    pub fn new(messages: Vec<Message>) -> Self {
+        Self {
+            messages,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the model to use instead of the client's default
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Sets the sampling temperature
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the max number of tokens to generate
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the sequences at which generation stops
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Sets the tools the model may call
+    pub fn with_tools(mut self, tools: Vec<ToolDefinition>) -> Self {
+        self.tools = tools;
+        self
+    }
+}
+
+/// Token counts reported by the provider for a completion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+/// A chat completion returned by the model
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatResponse {
+    /// The generated text
+    pub content: String,
+    /// Tools the model asked to call
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The model that generated the text, if reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Why generation stopped, if reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    /// Token counts, if reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl ChatResponse {
+    /// Creates a response with the given text
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            ..Self::default()
+        }
+    }
+}
+
+/// What a streamed reply sends while it is generated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// The next piece of the reply
+    Token(String),
+    /// A retried attempt starts over, so the tokens received so far should be discarded
+    Restart,
+}
+
+/// A provider of chat completions
+///
+/// Implementations report failures as [`NodeError`]s so they are retried according to the
+/// node's retry policy, e.g. [`NodeError::RateLimited`] for rate limits.
+#[async_trait]
+pub trait LlmClient: Send + Sync {
+    /// Generates the next message of the conversation
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, NodeError>;
+
+    /// Generates the next message of the conversation, sending its text to `events` as
+    /// [`StreamEvent::Token`]s as it is generated
+    ///
+    /// The returned response holds the complete text. The default implementation sends the
+    /// whole reply as a single token once [`chat`](LlmClient::chat) returns.
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        events: &UnboundedSender<StreamEvent>,
+    ) -> Result<ChatResponse, NodeError> {
+        let response = self.chat(request).await?;
+        let _ = events.send(StreamEvent::Token(response.content.clone()));
+        Ok(response)
+    }
+}
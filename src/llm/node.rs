@@ -0,0 +1,348 @@
+use super::{
+    ChatRequest, ChatResponse, ContextBudget, ConversationMemory, HeuristicTokenizer, LlmClient,
+    Message, StreamEvent, Tokenizer, Usage,
+};
+use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError, NodeOutput};
+use crate::prompt::PromptTemplate;
+use crate::shared::SharedData;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Logic asking a language model for a chat completion
+///
+/// The prompt is read from the shared data, either as a string sent as a user message or as a
+/// list of [`Message`]s, or rendered from a [`PromptTemplate`] and sent as a user message. The
+/// text of the reply is stored under the output key.
+///
+/// With [`with_stream`](LlmNode::with_stream) the reply is also sent token by token while it is
+/// generated. A retried attempt first sends [`StreamEvent::Restart`] and then streams its reply
+/// again from the start.
+///
+/// With [`with_memory`](LlmNode::with_memory) the history of a [`ConversationMemory`] is sent
+/// ahead of the prompt, and the prompt and reply are added to it. With
+/// [`with_context_budget`](LlmNode::with_context_budget) oversized requests are truncated or
+/// rejected before they reach the model.
+///
+/// The tokens used by every call are recorded in the run executing the node and reported in
+/// [`FlowResult::usage`](crate::flow::FlowResult::usage), or
+/// [`FlowError::usage`](crate::flow::FlowError::usage) if the run fails. When the provider does
+/// not report them they are estimated with the tokenizer of the context budget, or the
+/// [`HeuristicTokenizer`] without one.
+#[derive(Clone)]
+pub struct LlmNode {
+    client: Arc<dyn LlmClient>,
+    prompt: Prompt,
+    output_key: String,
+    system: Option<String>,
+    options: ChatRequest,
+    stream: Option<UnboundedSender<StreamEvent>>,
+    memory_key: Option<String>,
+    budget: Option<ContextBudget>,
+}
+
+impl LlmNode {
+    /// Creates logic sending the prompt stored under `prompt_key` to `client`
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client used to call the model
+    /// * `prompt_key` - The shared data key holding the prompt
+    /// * `output_key` - The shared data key the reply is stored under
+    pub fn new(client: Arc<dyn LlmClient>, prompt_key: &str, output_key: &str) -> Self {
+        Self {
+            client,
+            prompt: Prompt::Key(prompt_key.to_string()),
+            output_key: output_key.to_string(),
+            system: None,
+            options: ChatRequest::default(),
+            stream: None,
+            memory_key: None,
+            budget: None,
+        }
+    }
+
+    /// Creates logic sending the prompt rendered from `template` to `client`
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client used to call the model
+    /// * `template` - The template rendered with the shared data
+    /// * `output_key` - The shared data key the reply is stored under
+    pub fn from_template(
+        client: Arc<dyn LlmClient>,
+        template: PromptTemplate,
+        output_key: &str,
+    ) -> Self {
+        Self {
+            prompt: Prompt::Template(template),
+            ..Self::new(client, "", output_key)
+        }
+    }
+
+    /// Sets a system message sent before the prompt
+    pub fn with_system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// Sets the model to use instead of the client's default
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.options = self.options.with_model(model);
+        self
+    }
+
+    /// Sets the sampling temperature
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.options = self.options.with_temperature(temperature);
+        self
+    }
+
+    /// Sets the max number of tokens to generate
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.options = self.options.with_max_tokens(max_tokens);
+        self
+    }
+
+    /// Sets the sequences at which generation stops
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.options = self.options.with_stop(stop);
+        self
+    }
+
+    /// Streams the reply to `events` while it is generated
+    pub fn with_stream(mut self, events: UnboundedSender<StreamEvent>) -> Self {
+        self.stream = Some(events);
+        self
+    }
+
+    /// Keeps the conversation in the [`ConversationMemory`] stored under `memory_key`
+    pub fn with_memory(mut self, memory_key: &str) -> Self {
+        self.memory_key = Some(memory_key.to_string());
+        self
+    }
+
+    /// Checks that requests fit `budget` before sending them
+    pub fn with_context_budget(mut self, budget: ContextBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Creates a node with the given name running this logic
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?.with_async_logic(self))
+    }
+
+    /// Returns the shared data key the reply is stored under
+    pub(super) fn output_key(&self) -> &str {
+        &self.output_key
+    }
+
+    /// Builds the request for the prompt in the shared data
+    pub(super) fn request(&self, shared: &SharedData) -> Result<ChatRequest, NodeError> {
+        Ok(self.request_for(self.conversation(shared)?))
+    }
+
+    /// Returns the remembered history followed by the prompt, excluding the system message
+    pub(super) fn conversation(&self, shared: &SharedData) -> Result<Vec<Message>, NodeError> {
+        let mut conversation = match &self.memory_key {
+            Some(key) => ConversationMemory::load(shared, key)?.history(),
+            None => Vec::new(),
+        };
+        conversation.extend(self.prompt(shared)?);
+        Ok(conversation)
+    }
+
+    /// Adds the prompt and `reply` to the memory, if the node has one
+    pub(super) fn remember(&self, shared: &SharedData, reply: &str) -> Result<(), NodeError> {
+        let Some(key) = &self.memory_key else {
+            return Ok(());
+        };
+        let mut memory = ConversationMemory::load(shared, key)?;
+        memory.messages.extend(self.prompt(shared)?);
+        memory.push(Message::assistant(reply));
+        memory.save(shared, key)
+    }
+
+    /// Builds the request continuing `conversation`, which excludes the system message
+    pub(super) fn request_for(&self, conversation: Vec<Message>) -> ChatRequest {
+        let mut messages: Vec<Message> = self.system.iter().map(Message::system).collect();
+        messages.extend(conversation);
+        ChatRequest {
+            messages,
+            ..self.options.clone()
+        }
+    }
+
+    /// Sends `request` to the model, streaming the reply if configured, and records its usage
+    ///
+    /// Fails without calling the model if the request does not fit the context budget.
+    pub(super) async fn complete(
+        &self,
+        mut request: ChatRequest,
+        ctx: &ExecContext,
+    ) -> Result<ChatResponse, NodeError> {
+        if let Some(budget) = &self.budget {
+            budget.fit(&mut request)?;
+        }
+        let response = match &self.stream {
+            Some(events) => {
+                if ctx.attempt() > 0 {
+                    let _ = events.send(StreamEvent::Restart);
+                }
+                self.client.chat_stream(&request, events).await
+            }
+            None => self.client.chat(&request).await,
+        }?;
+        let tokenizer = self.budget.as_ref().map(ContextBudget::tokenizer);
+        record_usage(
+            ctx,
+            &request,
+            &response,
+            tokenizer.map_or(&HeuristicTokenizer, |t| t.as_ref()),
+        );
+        Ok(response)
+    }
+
+    /// Returns the conversation held by the prompt in the shared data
+    pub(super) fn prompt(&self, shared: &SharedData) -> Result<Vec<Message>, NodeError> {
+        match &self.prompt {
+            Prompt::Template(template) => Ok(vec![Message::user(template.render(shared)?)]),
+            Prompt::Key(key) => match shared.get_value(key) {
+                Some(Value::String(prompt)) => Ok(vec![Message::user(prompt)]),
+                Some(_) => shared.require(key),
+                None => Err(NodeError::MissingSharedData(key.clone())),
+            },
+        }
+    }
+}
+
+/// Where the prompt of an [`LlmNode`] comes from
+#[derive(Clone)]
+enum Prompt {
+    Key(String),
+    Template(PromptTemplate),
+}
+
+/// Records the tokens `response` used in the run executing the node
+///
+/// Usage not reported by the provider, as is common when streaming, is estimated with `tokenizer`.
+pub(super) fn record_usage(
+    ctx: &ExecContext,
+    request: &ChatRequest,
+    response: &ChatResponse,
+    tokenizer: &dyn Tokenizer,
+) {
+    let usage = response.usage.unwrap_or_else(|| {
+        let calls = response
+            .tool_calls
+            .iter()
+            .map(|call| tokenizer.count(&call.name) + tokenizer.count(&call.arguments.to_string()));
+        let prompt_tokens = tokenizer.count_messages(&request.messages) as u32;
+        let completion_tokens = (tokenizer.count(&response.content) + calls.sum::<usize>()) as u32;
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    });
+    let model = response
+        .model
+        .as_deref()
+        .or(request.model.as_deref())
+        .unwrap_or("unknown");
+    ctx.record_usage(model, usage);
+}
+
+/// Converts a value produced by this module back into its type
+pub(super) fn from_value<T: serde::de::DeserializeOwned>(value: &Value) -> Result<T, NodeError> {
+    T::deserialize(value).map_err(|e| NodeError::ExecutionError(e.to_string()))
+}
+
+/// Converts a type of this module into a value passed between node phases
+pub(super) fn to_value<T: serde::Serialize>(value: &T) -> Result<Value, NodeError> {
+    serde_json::to_value(value).map_err(|e| NodeError::ExecutionError(e.to_string()))
+}
+
+#[async_trait]
+impl AsyncNodeLogic for LlmNode {
+    async fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        to_value(&self.request(shared)?)
+    }
+
+    async fn exec(&self, prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let request: ChatRequest = from_value(prep_res)?;
+        to_value(&self.complete(request, ctx).await?)
+    }
+
+    async fn post(
+        &self,
+        shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        let response: ChatResponse = from_value(&exec_res)?;
+        self.remember(shared, &response.content)?;
+        shared.set_value(self.output_key.clone(), response.content);
+        Ok(NodeOutput::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryPolicy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+
+    /// Streams part of a reply and fails on the first call, then streams the whole reply
+    #[derive(Default)]
+    struct Flaky {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LlmClient for Flaky {
+        async fn chat(&self, _request: &ChatRequest) -> Result<ChatResponse, NodeError> {
+            unreachable!("the node streams")
+        }
+
+        async fn chat_stream(
+            &self,
+            _request: &ChatRequest,
+            events: &UnboundedSender<StreamEvent>,
+        ) -> Result<ChatResponse, NodeError> {
+            let _ = events.send(StreamEvent::Token("Hel".to_string()));
+            if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(NodeError::ExecutionError("connection reset".to_string()));
+            }
+            let _ = events.send(StreamEvent::Token("lo".to_string()));
+            Ok(ChatResponse::new("Hello"))
+        }
+    }
+
+    #[tokio::test]
+    async fn retried_streams_send_restart() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let node = LlmNode::new(Arc::new(Flaky::default()), "prompt", "reply")
+            .with_stream(tx)
+            .into_node("ask")
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new(1).unwrap());
+        let shared = SharedData::new();
+        shared.set("prompt", "Hi").unwrap();
+        node.exec_async(&shared).await.unwrap();
+        drop(node);
+
+        let mut text = String::new();
+        while let Some(event) = rx.recv().await {
+            match event {
+                StreamEvent::Token(token) => text.push_str(&token),
+                StreamEvent::Restart => text.clear(),
+            }
+        }
+        assert_eq!(text, "Hello");
+        assert_eq!(shared.get_value("reply"), Some(Value::from("Hello")));
+    }
+}
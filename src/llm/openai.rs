@@ -0,0 +1,860 @@
+use super::{
+    ChatRequest, ChatResponse, LlmClient, Message, Role, StreamEvent, ToolCall, ToolDefinition,
+    Usage,
+};
+use crate::core::NodeError;
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Base URL of the OpenAI API
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Client for servers speaking the OpenAI `/chat/completions` protocol
+///
+/// Besides the OpenAI API this works with local servers such as llama.cpp, vLLM and Ollama by
+/// pointing the base URL at them, e.g. `http://localhost:11434/v1`.
+///
+/// Rate limits are reported as [`NodeError::RateLimited`] and other unsuccessful responses as
+/// [`NodeError::ProviderError`], so server errors and rate limits are retried by the node.
+/// Streamed replies are read from the server-sent events of a `stream: true` request. An error
+/// event, or a stream ending without `[DONE]`, fails the reply so it is retried as well.
+#[derive(Debug, Clone)]
+pub struct OpenAiClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiClient {
+    /// Creates a client sending requests for `model` to the server at `base_url`
+    pub fn new(base_url: impl Into<String>, model: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+            model: model.into(),
+        }
+    }
+
+    /// Creates a client for `model` configured from the environment
+    ///
+    /// The API key is read from `OPENAI_API_KEY` and the base URL from `OPENAI_BASE_URL`,
+    /// defaulting to [`OPENAI_BASE_URL`].
+    pub fn from_env(model: impl Into<String>) -> Self {
+        let base_url = env::var("OPENAI_BASE_URL").unwrap_or_else(|_| OPENAI_BASE_URL.into());
+        let client = Self::new(base_url, model);
+        match env::var("OPENAI_API_KEY") {
+            Ok(key) => client.with_api_key(key),
+            Err(_) => client,
+        }
+    }
+
+    /// Sets the key sent as bearer token
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Sets the HTTP client used to send requests, e.g. to configure proxies or timeouts
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Returns the base URL requests are sent to
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Returns the model used when a request does not name one
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Sends `body` to the chat completions endpoint, failing on unsuccessful responses
+    pub(super) async fn send(
+        &self,
+        body: &CompletionBody<'_>,
+    ) -> Result<reqwest::Response, NodeError> {
+        let mut request = self
+            .http
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(body);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| NodeError::ExecutionError(format!("Request failed: {e}")))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let text = response.text().await.unwrap_or_default();
+        Err(provider_error(status, error_message(&text)))
+    }
+
+    /// Builds the request body for `request`
+    pub(super) fn body<'a>(&'a self, request: &'a ChatRequest, stream: bool) -> CompletionBody<'a> {
+        CompletionBody {
+            model: request.model.as_deref().unwrap_or(&self.model),
+            messages: request.messages.iter().map(WireMessage::from).collect(),
+            tools: request.tools.iter().map(WireTool::from).collect(),
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+            stop: &request.stop,
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmClient for OpenAiClient {
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, NodeError> {
+        let response = self.send(&self.body(request, false)).await?;
+        let completion: Completion = response
+            .json()
+            .await
+            .map_err(|e| NodeError::ExecutionError(format!("Invalid completion: {e}")))?;
+        let choice = completion
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| NodeError::ExecutionError("Completion has no choices".into()))?;
+        Ok(ChatResponse {
+            content: choice.message.content.unwrap_or_default(),
+            tool_calls: choice
+                .message
+                .tool_calls
+                .into_iter()
+                .map(ToolCall::from)
+                .collect(),
+            model: completion.model,
+            finish_reason: choice.finish_reason,
+            usage: completion.usage,
+        })
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        events: &UnboundedSender<StreamEvent>,
+    ) -> Result<ChatResponse, NodeError> {
+        let mut response = self.send(&self.body(request, true)).await?;
+        let mut sse = SseBuffer::default();
+        let mut reply = ChatResponse::default();
+        let mut calls: Vec<WireToolCall> = Vec::new();
+        loop {
+            let chunk = response
+                .chunk()
+                .await
+                .map_err(|e| NodeError::ExecutionError(format!("Stream failed: {e}")))?;
+            let Some(chunk) = chunk else {
+                return Err(NodeError::ExecutionError(
+                    "Stream ended before the reply was complete".into(),
+                ));
+            };
+            for data in sse.push(&chunk) {
+                if data == "[DONE]" {
+                    reply.tool_calls = calls.into_iter().map(ToolCall::from).collect();
+                    return Ok(reply);
+                }
+                let delta: CompletionChunk = serde_json::from_str(&data)
+                    .map_err(|e| NodeError::ExecutionError(format!("Invalid chunk: {e}")))?;
+                if let Some(error) = delta.error {
+                    return Err(stream_error(&error));
+                }
+                reply.model = delta.model.or(reply.model);
+                reply.usage = delta.usage.or(reply.usage);
+                for choice in delta.choices {
+                    reply.finish_reason = choice.finish_reason.or(reply.finish_reason);
+                    if let Some(token) = choice.delta.content.filter(|t| !t.is_empty()) {
+                        reply.content.push_str(&token);
+                        let _ = events.send(StreamEvent::Token(token));
+                    }
+                    for part in choice.delta.tool_calls {
+                        if calls.len() <= part.index {
+                            calls.resize_with(part.index + 1, WireToolCall::default);
+                        }
+                        let call = &mut calls[part.index];
+                        call.id.push_str(part.id.as_deref().unwrap_or_default());
+                        if let Some(function) = part.function {
+                            call.function
+                                .name
+                                .push_str(&function.name.unwrap_or_default());
+                            call.function
+                                .arguments
+                                .push_str(&function.arguments.unwrap_or_default());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Splits a server-sent event stream into the data of its events
+#[derive(Default)]
+struct SseBuffer {
+    pending: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseBuffer {
+    /// Adds received bytes, returning the data of every event completed by them
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data
+                    .push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+        }
+        events
+    }
+}
+
+/// Maps an unsuccessful response to the error reported to the node
+fn provider_error(status: StatusCode, message: String) -> NodeError {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return NodeError::RateLimited(message);
+    }
+    NodeError::ProviderError {
+        status: status.as_u16(),
+        message,
+    }
+}
+
+/// Maps an error event sent in place of a chunk, which fails the reply part way through
+///
+/// The status is taken from a numeric `code` when there is one. Otherwise the error is reported
+/// as a server error, or as a rate limit if its code or type says so.
+fn stream_error(error: &Value) -> NodeError {
+    let message = match &error["message"] {
+        Value::String(message) => message.clone(),
+        _ => error.to_string(),
+    };
+    let code = error["code"]
+        .as_u64()
+        .or_else(|| error["code"].as_str()?.parse().ok())
+        .and_then(|code| StatusCode::from_u16(u16::try_from(code).ok()?).ok());
+    let rate_limited = [&error["code"], &error["type"]]
+        .iter()
+        .any(|v| v.as_str().is_some_and(|s| s.contains("rate_limit")));
+    let status = match code {
+        Some(code) => code,
+        None if rate_limited => StatusCode::TOO_MANY_REQUESTS,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    provider_error(status, message)
+}
+
+/// Returns the message of an OpenAI error body, or the body itself
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string())
+}
+
+#[derive(Serialize)]
+pub(super) struct CompletionBody<'a> {
+    model: &'a str,
+    messages: Vec<WireMessage<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<WireTool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// Asks for the usage of a streamed reply, sent in a last chunk without choices
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct Completion {
+    #[serde(default)]
+    model: Option<String>,
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+    /// Sent instead of a chunk when generation fails part way through
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: ChunkDelta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChoiceMessage {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<WireToolCall>,
+}
+
+#[derive(Default, Deserialize)]
+struct ChunkDelta {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ChunkToolCall>,
+}
+
+/// A piece of a tool call, continuing the call at the same index
+#[derive(Deserialize)]
+struct ChunkToolCall {
+    index: usize,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    function: Option<ChunkFunction>,
+}
+
+#[derive(Deserialize)]
+struct ChunkFunction {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    arguments: Option<String>,
+}
+
+/// A message in the OpenAI format, where tool calls carry their arguments as a JSON string
+#[derive(Serialize)]
+pub(super) struct WireMessage<'a> {
+    role: Role,
+    content: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<WireToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+}
+
+impl<'a> From<&'a Message> for WireMessage<'a> {
+    fn from(message: &'a Message) -> Self {
+        let empty = message.content.is_empty() && !message.tool_calls.is_empty();
+        Self {
+            role: message.role,
+            content: (!empty).then_some(message.content.as_str()),
+            tool_calls: message.tool_calls.iter().map(WireToolCall::from).collect(),
+            tool_call_id: message.tool_call_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct WireToolCall {
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    kind: String,
+    function: WireFunction,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct WireFunction {
+    name: String,
+    #[serde(default)]
+    arguments: String,
+}
+
+fn function_type() -> String {
+    "function".into()
+}
+
+impl From<&ToolCall> for WireToolCall {
+    fn from(call: &ToolCall) -> Self {
+        Self {
+            id: call.id.clone(),
+            kind: function_type(),
+            function: WireFunction {
+                name: call.name.clone(),
+                arguments: call.arguments.to_string(),
+            },
+        }
+    }
+}
+
+impl From<WireToolCall> for ToolCall {
+    /// Arguments that are not valid JSON are kept as a string, to be rejected by the tool
+    fn from(call: WireToolCall) -> Self {
+        let arguments = match call.function.arguments.trim() {
+            "" => Value::Object(Default::default()),
+            text => serde_json::from_str(text).unwrap_or(Value::String(call.function.arguments)),
+        };
+        Self {
+            id: call.id,
+            name: call.function.name,
+            arguments,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct WireTool<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: &'a ToolDefinition,
+}
+
+impl<'a> From<&'a ToolDefinition> for WireTool<'a> {
+    fn from(function: &'a ToolDefinition) -> Self {
+        Self {
+            kind: "function",
+            function,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Node, NodeError};
+    use crate::llm::LlmNode;
+    use crate::retry::RetryPolicy;
+    use crate::shared::SharedData;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A canned HTTP response of the mock server
+    struct Reply {
+        status: u16,
+        content_type: &'static str,
+        body: String,
+    }
+
+    fn json_reply(status: u16, body: Value) -> Reply {
+        Reply {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    /// Serves `replies` in order, one per connection, and records the request bodies
+    async fn serve(replies: Vec<Reply>) -> (OpenAiClient, Arc<Mutex<Vec<Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for reply in replies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let body = read_request(&mut stream).await;
+                received.lock().unwrap().push(body);
+                let head = format!(
+                    "HTTP/1.1 {} Mock\r\ncontent-type: {}\r\nconnection: close\r\n\r\n",
+                    reply.status, reply.content_type
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(reply.body.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        let client = OpenAiClient::new(url, "mock-model").with_api_key("secret");
+        (client, requests)
+    }
+
+    /// Reads one request and returns its JSON body
+    async fn read_request(stream: &mut TcpStream) -> Value {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&data);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if data.len() >= end + 4 + length {
+                    return serde_json::from_slice(&data[end + 4..end + 4 + length]).unwrap();
+                }
+            }
+            if n == 0 {
+                panic!("connection closed before the request was complete");
+            }
+        }
+    }
+
+    fn completion(message: Value) -> Value {
+        json!({
+            "model": "mock-model-1",
+            "choices": [{ "message": message, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 7, "completion_tokens": 3, "total_tokens": 10 }
+        })
+    }
+
+    fn request() -> ChatRequest {
+        ChatRequest::new(vec![Message::user("Hi")])
+    }
+
+    #[tokio::test]
+    async fn sends_the_request_and_reads_the_reply() {
+        let reply = completion(json!({ "role": "assistant", "content": "Hello" }));
+        let (client, requests) = serve(vec![json_reply(200, reply)]).await;
+        let response = client
+            .chat(&request().with_temperature(0.5).with_max_tokens(20))
+            .await
+            .unwrap();
+        assert_eq!(response.content, "Hello");
+        assert_eq!(response.model.as_deref(), Some("mock-model-1"));
+        assert_eq!(response.usage.unwrap().total_tokens, 10);
+
+        let body = requests.lock().unwrap().remove(0);
+        assert_eq!(body["model"], "mock-model");
+        assert_eq!(
+            body["messages"],
+            json!([{ "role": "user", "content": "Hi" }])
+        );
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["max_tokens"], 20);
+        assert!(body.get("stream").is_none());
+        assert!(body.get("stream_options").is_none());
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_is_rate_limited() {
+        let error = json!({ "error": { "message": "Slow down" } });
+        let (client, _) = serve(vec![json_reply(429, error)]).await;
+        let err = client.chat(&request()).await.unwrap_err();
+        assert!(matches!(&err, NodeError::RateLimited(message) if message == "Slow down"));
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_request_timeouts_are_retryable() {
+        for status in [500, 503, 408] {
+            let error = json!({ "error": { "message": "Try again", "type": "server_error" } });
+            let (client, _) = serve(vec![json_reply(status, error)]).await;
+            let err = client.chat(&request()).await.unwrap_err();
+            assert!(
+                matches!(&err, NodeError::ProviderError { status: s, message } if *s == status && message == "Try again")
+            );
+            assert!(err.is_retryable(), "{status}");
+        }
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retryable() {
+        for status in [400, 401, 404] {
+            let error = json!({ "error": { "message": "Bad key" } });
+            let (client, _) = serve(vec![json_reply(status, error)]).await;
+            let err = client.chat(&request()).await.unwrap_err();
+            assert!(matches!(&err, NodeError::ProviderError { status: s, .. } if *s == status));
+            assert!(!err.is_retryable(), "{status}");
+        }
+    }
+
+    #[tokio::test]
+    async fn error_bodies_that_are_not_json_are_reported_as_is() {
+        let reply = Reply {
+            status: 502,
+            content_type: "text/plain",
+            body: "  Bad gateway\n".to_string(),
+        };
+        let (client, _) = serve(vec![reply]).await;
+        let err = client.chat(&request()).await.unwrap_err();
+        assert!(
+            matches!(err, NodeError::ProviderError { message, .. } if message == "Bad gateway")
+        );
+    }
+
+    #[tokio::test]
+    async fn nodes_retry_server_errors_but_not_client_errors() {
+        let reply = completion(json!({ "role": "assistant", "content": "Hello" }));
+        let error = json!({ "error": { "message": "Overloaded" } });
+        let (client, requests) =
+            serve(vec![json_reply(503, error.clone()), json_reply(200, reply)]).await;
+        let node = llm_node(client);
+        let shared = SharedData::new();
+        shared.set("prompt", "Hi").unwrap();
+        node.exec_async(&shared).await.unwrap();
+        assert_eq!(shared.get_value("reply"), Some(json!("Hello")));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (client, requests) = serve(vec![json_reply(401, error)]).await;
+        let err = llm_node(client).exec_async(&shared).await.unwrap_err();
+        assert!(matches!(err, NodeError::ProviderError { status: 401, .. }));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    fn llm_node(client: OpenAiClient) -> Node {
+        LlmNode::new(Arc::new(client), "prompt", "reply")
+            .into_node("ask")
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new(2).unwrap())
+    }
+
+    #[tokio::test]
+    async fn decodes_tool_calls() {
+        let reply = completion(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [
+                { "id": "call_1", "type": "function",
+                  "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" } },
+                { "id": "call_2", "type": "function",
+                  "function": { "name": "weather", "arguments": "{city: Paris" } },
+                { "id": "call_3", "type": "function",
+                  "function": { "name": "time", "arguments": "" } }
+            ]
+        }));
+        let (client, _) = serve(vec![json_reply(200, reply)]).await;
+        let response = client.chat(&request()).await.unwrap();
+        assert_eq!(response.content, "");
+        let calls: Vec<_> = response
+            .tool_calls
+            .iter()
+            .map(|call| (call.id.as_str(), call.name.as_str(), call.arguments.clone()))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                ("call_1", "weather", json!({ "city": "Paris" })),
+                ("call_2", "weather", json!("{city: Paris")),
+                ("call_3", "time", json!({})),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_tool_calls_and_results_in_the_openai_format() {
+        let reply = completion(json!({ "role": "assistant", "content": "Sunny" }));
+        let (client, requests) = serve(vec![json_reply(200, reply)]).await;
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: "weather".to_string(),
+            arguments: json!({ "city": "Paris" }),
+        };
+        let tool = ToolDefinition {
+            name: "weather".to_string(),
+            description: "Looks up the weather".to_string(),
+            parameters: json!({ "type": "object" }),
+        };
+        let request = ChatRequest::new(vec![
+            Message::user("Weather?"),
+            Message::assistant("").with_tool_calls(vec![call]),
+            Message::tool("call_1", "Sunny"),
+        ])
+        .with_tools(vec![tool]);
+        client.chat(&request).await.unwrap();
+
+        let body = requests.lock().unwrap().remove(0);
+        assert_eq!(
+            body["messages"][1],
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{ "id": "call_1", "type": "function",
+                    "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" } }]
+            })
+        );
+        assert_eq!(
+            body["messages"][2],
+            json!({ "role": "tool", "content": "Sunny", "tool_call_id": "call_1" })
+        );
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["tools"][0]["function"]["name"], "weather");
+    }
+
+    fn sse_reply(events: &[Value], done: bool) -> Reply {
+        let mut body: String = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .collect();
+        if done {
+            body.push_str("data: [DONE]\n\n");
+        }
+        Reply {
+            status: 200,
+            content_type: "text/event-stream",
+            body,
+        }
+    }
+
+    fn token(text: &str) -> Value {
+        json!({ "model": "mock-model-1", "choices": [{ "delta": { "content": text } }] })
+    }
+
+    #[test]
+    fn sse_buffer_joins_events_split_across_chunks() {
+        let mut events = SseBuffer::default();
+        assert!(events.push(b"data: {\"a\"").is_empty());
+        assert!(events.push(b":1}\n").is_empty());
+        assert_eq!(events.push(b"\ndata: [DO"), ["{\"a\":1}"]);
+        assert_eq!(events.push(b"NE]\n\n"), ["[DONE]"]);
+    }
+
+    #[test]
+    fn sse_buffer_handles_crlf_comments_and_multi_line_data() {
+        let mut events = SseBuffer::default();
+        let stream = b": keep-alive\r\n\r\nevent: message\r\ndata: first\r\ndata:second\r\n\r\n";
+        assert_eq!(events.push(stream), ["first\nsecond"]);
+    }
+
+    #[test]
+    fn sse_buffer_keeps_multibyte_characters_split_across_chunks() {
+        let mut events = SseBuffer::default();
+        let bytes = "data: caf\u{e9}\n\n".as_bytes();
+        assert!(events.push(&bytes[..9]).is_empty());
+        assert_eq!(events.push(&bytes[9..]), ["caf\u{e9}"]);
+    }
+
+    #[tokio::test]
+    async fn streams_tokens_and_assembles_the_reply() {
+        let usage = json!({ "choices": [], "usage":
+            { "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 } });
+        let finish = json!({ "choices": [{ "delta": {}, "finish_reason": "stop" }] });
+        let reply = sse_reply(&[token("Hel"), token("lo"), finish, usage], true);
+        let (client, requests) = serve(vec![reply]).await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = client.chat_stream(&request(), &tx).await.unwrap();
+        assert_eq!(response.content, "Hello");
+        assert_eq!(response.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(response.usage.unwrap().total_tokens, 7);
+        drop(tx);
+        let mut tokens = Vec::new();
+        while let Some(token) = rx.recv().await {
+            tokens.push(token);
+        }
+        assert_eq!(
+            tokens,
+            ["Hel", "lo"].map(|t| StreamEvent::Token(t.to_string()))
+        );
+        let body = requests.lock().unwrap().remove(0);
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+    }
+
+    #[tokio::test]
+    async fn assembles_tool_calls_from_deltas() {
+        let part = |index: usize, function: Value, id: Option<&str>| {
+            let mut call = json!({ "index": index, "function": function });
+            if let Some(id) = id {
+                call["id"] = json!(id);
+                call["type"] = json!("function");
+            }
+            json!({ "choices": [{ "delta": { "tool_calls": [call] } }] })
+        };
+        let events = [
+            part(
+                0,
+                json!({ "name": "weather", "arguments": "" }),
+                Some("call_1"),
+            ),
+            part(
+                1,
+                json!({ "name": "time", "arguments": "{\"zone\"" }),
+                Some("call_2"),
+            ),
+            part(0, json!({ "arguments": "{\"city\":" }), None),
+            part(0, json!({ "arguments": "\"Paris\"}" }), None),
+            part(1, json!({ "arguments": ":\"UTC\"}" }), None),
+        ];
+        let (client, _) = serve(vec![sse_reply(&events, true)]).await;
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = client.chat_stream(&request(), &tx).await.unwrap();
+        let calls: Vec<_> = response
+            .tool_calls
+            .iter()
+            .map(|call| (call.id.as_str(), call.name.as_str(), call.arguments.clone()))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                ("call_1", "weather", json!({ "city": "Paris" })),
+                ("call_2", "time", json!({ "zone": "UTC" })),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn error_events_fail_the_stream() {
+        let error = json!({ "error": { "message": "Overloaded", "type": "server_error" } });
+        let (client, _) = serve(vec![sse_reply(&[token("Hel"), error], false)]).await;
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let err = client.chat_stream(&request(), &tx).await.unwrap_err();
+        assert!(
+            matches!(&err, NodeError::ProviderError { status: 500, message } if message == "Overloaded")
+        );
+        assert!(err.is_retryable());
+
+        let error = json!({ "error": { "message": "Slow down", "code": "rate_limit_exceeded" } });
+        let (client, _) = serve(vec![sse_reply(&[error], false)]).await;
+        let err = client.chat_stream(&request(), &tx).await.unwrap_err();
+        assert!(matches!(err, NodeError::RateLimited(_)));
+
+        let error = json!({ "error": { "message": "Bad request", "code": 400 } });
+        let (client, _) = serve(vec![sse_reply(&[error], false)]).await;
+        let err = client.chat_stream(&request(), &tx).await.unwrap_err();
+        assert!(matches!(err, NodeError::ProviderError { status: 400, .. }));
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn streams_ending_without_done_fail() {
+        let (client, _) = serve(vec![sse_reply(&[token("Hel")], false)]).await;
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let err = client.chat_stream(&request(), &tx).await.unwrap_err();
+        assert!(matches!(err, NodeError::ExecutionError(_)));
+        assert!(err.is_retryable());
+    }
+}
@@ -0,0 +1,342 @@
+//! Validation of JSON values against the JSON schemas derived by `schemars`
+//!
+//! Covers the keywords found in derived schemas: `$ref`, `type`, `enum`, `const`, object and
+//! array shapes, `anyOf`/`oneOf`/`allOf` and numeric, string and array bounds. Other keywords
+//! are ignored.
+//!
+//! `oneOf` is checked like `anyOf`: a value matching more than one option is accepted, as serde
+//! accepts it for the first matching variant of an untagged enum.
+use serde_json::{Map, Value};
+
+/// Checks `value` against `schema`, returning a description of the first mismatch
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    Validator { root: schema }.check(schema, value, "")
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl<'a> Validator<'a> {
+    fn check(&self, schema: &'a Value, value: &Value, path: &str) -> Result<(), String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => return Err(mismatch(path, "no value is allowed")),
+            Value::Object(schema) => schema,
+            _ => return Ok(()),
+        };
+        let at = |message: String| mismatch(path, &message);
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let target = self
+                .resolve(reference)
+                .ok_or_else(|| at(format!("unresolvable schema reference '{reference}'")))?;
+            self.check(target, value, path)?;
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                _ => types.as_str().into_iter().collect(),
+            };
+            if !types.iter().any(|t| has_type(value, t)) {
+                return Err(at(format!(
+                    "expected {}, found {}",
+                    types.join(" or "),
+                    type_name(value)
+                )));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+            && !allowed.contains(value)
+        {
+            return Err(at(format!(
+                "expected one of {}",
+                Value::from(allowed.clone())
+            )));
+        }
+        if let Some(expected) = schema.get("const")
+            && expected != value
+        {
+            return Err(at(format!("expected {expected}")));
+        }
+
+        match value {
+            Value::Object(fields) => self.check_object(schema, fields, path)?,
+            Value::Array(items) => self.check_array(schema, items, path)?,
+            Value::String(s) => {
+                check_bounds(schema, s.chars().count(), "Length", "characters").map_err(at)?
+            }
+            Value::Number(n) => check_number(schema, n.as_f64().unwrap_or_default()).map_err(at)?,
+            _ => {}
+        }
+
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            for sub in all {
+                self.check(sub, value, path)?;
+            }
+        }
+        // A value matching several `oneOf` options is accepted, see the module docs
+        for keyword in ["anyOf", "oneOf"] {
+            let Some(options) = schema.get(keyword).and_then(Value::as_array) else {
+                continue;
+            };
+            let errors: Vec<String> = options
+                .iter()
+                .filter_map(|sub| self.check(sub, value, path).err())
+                .collect();
+            if !options.is_empty() && errors.len() == options.len() {
+                return Err(match errors.as_slice() {
+                    [only] => only.clone(),
+                    _ => at(format!(
+                        "does not match any allowed schema ({})",
+                        errors.join("; ")
+                    )),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        fields: &Map<String, Value>,
+        path: &str,
+    ) -> Result<(), String> {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(name) = required.as_str()
+                && !fields.contains_key(name)
+            {
+                return Err(mismatch(path, &format!("missing required field '{name}'")));
+            }
+        }
+        for (name, field) in fields {
+            let field_path = format!("{path}/{name}");
+            match (
+                properties.and_then(|p| p.get(name)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(sub), _) => self.check(sub, field, &field_path)?,
+                (None, Some(Value::Bool(false))) => {
+                    return Err(mismatch(path, &format!("unexpected field '{name}'")));
+                }
+                (None, Some(sub)) => self.check(sub, field, &field_path)?,
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_array(
+        &self,
+        schema: &'a Map<String, Value>,
+        items: &[Value],
+        path: &str,
+    ) -> Result<(), String> {
+        check_bounds(schema, items.len(), "Items", "items").map_err(|e| mismatch(path, &e))?;
+        if let Some(sub) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                self.check(sub, item, &format!("{path}/{i}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a reference within the root schema, such as `#/$defs/Item`
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        self.root.pointer(reference.strip_prefix('#')?)
+    }
+}
+
+fn mismatch(path: &str, message: &str) -> String {
+    if path.is_empty() {
+        message.to_string()
+    } else {
+        format!("{path}: {message}")
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        name => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Checks `minLength`/`maxLength` or `minItems`/`maxItems`
+fn check_bounds(
+    schema: &Map<String, Value>,
+    len: usize,
+    keyword: &str,
+    unit: &str,
+) -> Result<(), String> {
+    let bound = |name: String| schema.get(&name).and_then(Value::as_u64);
+    if let Some(min) = bound(format!("min{keyword}"))
+        && (len as u64) < min
+    {
+        return Err(format!("expected at least {min} {unit}, found {len}"));
+    }
+    if let Some(max) = bound(format!("max{keyword}"))
+        && (len as u64) > max
+    {
+        return Err(format!("expected at most {max} {unit}, found {len}"));
+    }
+    Ok(())
+}
+
+fn check_number(schema: &Map<String, Value>, n: f64) -> Result<(), String> {
+    let bound = |name: &str| schema.get(name).and_then(Value::as_f64);
+    if let Some(min) = bound("minimum")
+        && n < min
+    {
+        return Err(format!("expected at least {min}, found {n}"));
+    }
+    if let Some(max) = bound("maximum")
+        && n > max
+    {
+        return Err(format!("expected at most {max}, found {n}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schemars::JsonSchema;
+    use serde_json::json;
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Order {
+        id: u32,
+        items: Vec<Item>,
+        note: Option<String>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Item {
+        name: String,
+        qty: u8,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    #[serde(untagged)]
+    enum Amount {
+        Whole(u32),
+        Fraction(f64),
+    }
+
+    fn schema_of<T: JsonSchema>() -> Value {
+        schemars::schema_for!(T).to_value()
+    }
+
+    #[test]
+    fn resolves_references_to_definitions() {
+        let schema = schema_of::<Order>();
+        assert!(schema["$defs"]["Item"].is_object());
+        let order = json!({ "id": 1, "items": [{ "name": "tea", "qty": 2 }], "note": null });
+        assert_eq!(validate(&schema, &order), Ok(()));
+
+        let order = json!({ "id": 1, "items": [{ "name": "tea", "qty": "two" }] });
+        assert_eq!(
+            validate(&schema, &order),
+            Err("/items/0/qty: expected integer, found string".to_string())
+        );
+        let order = json!({ "id": 1, "items": [{ "name": "tea" }] });
+        assert_eq!(
+            validate(&schema, &order),
+            Err("/items/0: missing required field 'qty'".to_string())
+        );
+        let order = json!({ "id": 1, "items": [{ "name": "tea", "qty": 300 }] });
+        assert!(
+            validate(&schema, &order)
+                .unwrap_err()
+                .contains("at most 255")
+        );
+    }
+
+    #[test]
+    fn unresolvable_references_are_reported() {
+        let schema = json!({ "$ref": "#/$defs/Missing" });
+        assert_eq!(
+            validate(&schema, &json!(1)),
+            Err("unresolvable schema reference '#/$defs/Missing'".to_string())
+        );
+    }
+
+    #[test]
+    fn one_of_accepts_values_matching_several_options() {
+        let schema = schema_of::<Amount>();
+        assert_eq!(validate(&schema, &json!(3)), Ok(()));
+        assert_eq!(validate(&schema, &json!(2.5)), Ok(()));
+        let err = validate(&schema, &json!("3")).unwrap_err();
+        assert!(err.contains("does not match any allowed schema"), "{err}");
+
+        let schema = json!({ "oneOf": [{ "type": "integer" }, { "minimum": 0 }] });
+        assert_eq!(validate(&schema, &json!(5)), Ok(()));
+        assert_eq!(
+            validate(&schema, &json!(-1.5)),
+            Err(
+                "does not match any allowed schema (expected integer, found number; \
+                 expected at least 0, found -1.5)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_additional_properties_when_disallowed() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "a": { "type": "string" } },
+            "additionalProperties": false
+        });
+        assert_eq!(validate(&schema, &json!({ "a": "x" })), Ok(()));
+        assert_eq!(
+            validate(&schema, &json!({ "a": "x", "b": 1 })),
+            Err("unexpected field 'b'".to_string())
+        );
+
+        let schema = json!({ "type": "object", "additionalProperties": { "type": "integer" } });
+        assert_eq!(validate(&schema, &json!({ "a": 1 })), Ok(()));
+        assert_eq!(
+            validate(&schema, &json!({ "a": "x" })),
+            Err("/a: expected integer, found string".to_string())
+        );
+    }
+
+    #[test]
+    fn checks_enums_consts_and_bounds() {
+        let schema = json!({ "enum": ["red", "green"] });
+        assert_eq!(validate(&schema, &json!("red")), Ok(()));
+        assert!(validate(&schema, &json!("blue")).is_err());
+        assert!(validate(&json!({ "const": 1 }), &json!(2)).is_err());
+        let schema = json!({ "type": "string", "minLength": 2, "maxLength": 3 });
+        assert!(validate(&schema, &json!("a")).is_err());
+        assert_eq!(validate(&schema, &json!("\u{e9}\u{e9}")), Ok(()));
+        let schema = json!({ "type": "array", "minItems": 1 });
+        assert!(validate(&schema, &json!([])).is_err());
+        assert!(validate(&json!(false), &json!(null)).is_err());
+        assert_eq!(validate(&json!(true), &json!(null)), Ok(()));
+    }
+}
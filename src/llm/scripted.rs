@@ -0,0 +1,53 @@
+//! A client replying from a script, for testing the nodes of this module
+use super::{ChatRequest, ChatResponse, LlmClient, ToolCall};
+use crate::core::NodeError;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Replies with the scripted responses in turn and keeps the requests it received
+#[derive(Default)]
+pub(super) struct Scripted {
+    replies: Mutex<VecDeque<ChatResponse>>,
+    requests: Mutex<Vec<ChatRequest>>,
+}
+
+impl Scripted {
+    /// Creates a client replying with `replies`, failing once they are used up
+    pub(super) fn new(replies: impl IntoIterator<Item = ChatResponse>) -> Arc<Self> {
+        Arc::new(Self {
+            replies: Mutex::new(replies.into_iter().collect()),
+            requests: Mutex::default(),
+        })
+    }
+
+    /// Returns the requests received so far
+    pub(super) fn requests(&self) -> Vec<ChatRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl LlmClient for Scripted {
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, NodeError> {
+        self.requests.lock().unwrap().push(request.clone());
+        self.replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| NodeError::ExecutionError("script has no more replies".to_string()))
+    }
+}
+
+/// Returns a reply calling the tool `name` with `arguments`
+pub(super) fn calling(name: &str, arguments: Value) -> ChatResponse {
+    ChatResponse {
+        tool_calls: vec![ToolCall {
+            id: format!("call_{name}"),
+            name: name.to_string(),
+            arguments,
+        }],
+        ..ChatResponse::default()
+    }
+}
@@ -0,0 +1,230 @@
+use super::node::{from_value, to_value};
+use super::{ChatRequest, LlmNode, Message, Role, schema};
+use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError, NodeOutput};
+use crate::shared::SharedData;
+use async_trait::async_trait;
+use schemars::{JsonSchema, schema_for};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::marker::PhantomData;
+
+/// Logic asking a language model for a value of type `T`
+///
+/// The model is told to reply with JSON matching the schema derived from `T`. The JSON is taken
+/// from the reply, also when wrapped in a fenced code block, checked against the schema and
+/// stored under the output key of the wrapped [`LlmNode`].
+///
+/// A reply that does not match fails the attempt with [`NodeError::InvalidOutput`]. When the
+/// node retries, the model is shown its reply and the problem with it, so the node's
+/// `max_retries` budget bounds how often it is asked to correct itself.
+pub struct StructuredNode<T> {
+    llm: LlmNode,
+    schema: Value,
+    output: PhantomData<fn() -> T>,
+}
+
+impl<T: JsonSchema + DeserializeOwned + 'static> StructuredNode<T> {
+    /// Creates logic asking the model configured in `llm` for a `T`
+    pub fn new(llm: LlmNode) -> Self {
+        Self {
+            llm,
+            schema: schema_for!(T).to_value(),
+            output: PhantomData,
+        }
+    }
+
+    /// Returns the JSON schema the reply must match
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// Creates a node with the given name running this logic
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?.with_async_logic(self))
+    }
+
+    /// Parses and checks a reply of the model
+    fn parse(&self, reply: &str) -> Result<Value, NodeError> {
+        let invalid = |message: String| NodeError::InvalidOutput {
+            message,
+            output: reply.to_string(),
+        };
+        let value = extract_json(reply).ok_or_else(|| invalid("reply contains no JSON".into()))?;
+        schema::validate(&self.schema, &value).map_err(invalid)?;
+        T::deserialize(&value).map_err(|e| invalid(e.to_string()))?;
+        Ok(value)
+    }
+}
+
+impl<T> Clone for StructuredNode<T> {
+    fn clone(&self) -> Self {
+        Self {
+            llm: self.llm.clone(),
+            schema: self.schema.clone(),
+            output: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: JsonSchema + DeserializeOwned + 'static> AsyncNodeLogic for StructuredNode<T> {
+    async fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        let mut request = self.llm.request(shared)?;
+        let instruction = format!(
+            "Reply only with JSON matching this JSON schema:\n{}",
+            self.schema
+        );
+        match request.messages.first_mut() {
+            Some(first) if first.role == Role::System => {
+                first.content = format!("{}\n\n{instruction}", first.content);
+            }
+            _ => request.messages.insert(0, Message::system(instruction)),
+        }
+        to_value(&request)
+    }
+
+    async fn exec(&self, prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let mut request: ChatRequest = from_value(prep_res)?;
+        if let Some(NodeError::InvalidOutput { message, output }) = ctx.last_error() {
+            request.messages.push(Message::assistant(output.clone()));
+            request.messages.push(Message::user(format!(
+                "That reply is invalid: {message}. Reply again with only the corrected JSON."
+            )));
+        }
+        let response = self.llm.complete(request, ctx).await?;
+        self.parse(&response.content)
+    }
+
+    async fn post(
+        &self,
+        shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        self.llm.remember(shared, &exec_res.to_string())?;
+        shared.set_value(self.llm.output_key(), exec_res);
+        Ok(NodeOutput::default())
+    }
+}
+
+/// Returns the JSON value in a reply, which may be wrapped in prose or a fenced code block
+fn extract_json(reply: &str) -> Option<Value> {
+    let parse = |text: &str| serde_json::from_str::<Value>(text.trim()).ok();
+    if let Some(value) = parse(reply) {
+        return Some(value);
+    }
+    let mut fences = reply.split("```").skip(1).step_by(2);
+    if let Some(value) = fences.find_map(|block| {
+        let body = block.split_once('\n').map_or(block, |(_, body)| body);
+        parse(body)
+    }) {
+        return Some(value);
+    }
+    let start = reply.find(['{', '['])?;
+    let end = reply.rfind(['}', ']'])?;
+    reply.get(start..=end).and_then(parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ChatResponse;
+    use crate::llm::scripted::Scripted;
+    use crate::retry::RetryPolicy;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[derive(Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct Answer {
+        value: u32,
+    }
+
+    fn structured(client: Arc<Scripted>) -> (Node, SharedData) {
+        let node = StructuredNode::<Answer>::new(LlmNode::new(client, "q", "answer"))
+            .into_node("structured")
+            .unwrap()
+            .with_retry_policy(RetryPolicy::new(1).unwrap());
+        let shared = SharedData::new();
+        shared.set("q", "Pick a number").unwrap();
+        (node, shared)
+    }
+
+    #[tokio::test]
+    async fn asks_the_model_to_correct_invalid_replies() {
+        let client = Scripted::new([
+            ChatResponse::new("three"),
+            ChatResponse::new("```json\n{\"value\": 3}\n```"),
+        ]);
+        let (node, shared) = structured(client.clone());
+        node.exec_async(&shared).await.unwrap();
+        assert_eq!(shared.get_value("answer"), Some(json!({ "value": 3 })));
+
+        let requests = client.requests();
+        assert!(requests[0].messages[0].content.contains("JSON schema"));
+        let retry = &requests[1].messages;
+        assert_eq!(retry.len(), requests[0].messages.len() + 2);
+        let [.., reply, correction] = retry.as_slice() else {
+            unreachable!()
+        };
+        assert_eq!(
+            (reply.role, reply.content.as_str()),
+            (Role::Assistant, "three")
+        );
+        assert_eq!(correction.role, Role::User);
+        assert!(
+            correction
+                .content
+                .starts_with("That reply is invalid: reply contains no JSON."),
+            "{}",
+            correction.content
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let client = Scripted::new([
+            ChatResponse::new("three"),
+            ChatResponse::new("{\"value\": -3}"),
+            ChatResponse::new("{\"value\": 3}"),
+        ]);
+        let (node, shared) = structured(client.clone());
+        let err = node.exec_async(&shared).await.unwrap_err();
+        assert!(
+            matches!(err, NodeError::RetryLimitExceeded { attempts: 1, .. }),
+            "{err}"
+        );
+        assert_eq!(client.requests().len(), 2);
+        assert!(!shared.contains_key("answer"));
+    }
+
+    #[test]
+    fn extracts_bare_json() {
+        assert_eq!(extract_json(" {\"a\": 1}\n"), Some(json!({ "a": 1 })));
+        assert_eq!(extract_json("[1, 2]"), Some(json!([1, 2])));
+    }
+
+    #[test]
+    fn extracts_json_from_fenced_blocks() {
+        let reply = "Here you go:\n```json\n{\"a\": 1}\n```\nAnything else?";
+        assert_eq!(extract_json(reply), Some(json!({ "a": 1 })));
+        let reply = "```\n[1]\n```";
+        assert_eq!(extract_json(reply), Some(json!([1])));
+        let reply = "```text\nnot json\n```\n```json\n{\"b\": 2}\n```";
+        assert_eq!(extract_json(reply), Some(json!({ "b": 2 })));
+    }
+
+    #[test]
+    fn extracts_json_wrapped_in_prose() {
+        let reply = "Sure! The result is {\"a\": {\"b\": [1, 2]}} as requested.";
+        assert_eq!(extract_json(reply), Some(json!({ "a": { "b": [1, 2] } })));
+    }
+
+    #[test]
+    fn returns_none_without_json() {
+        assert_eq!(extract_json("No idea, sorry."), None);
+        assert_eq!(extract_json("{ broken"), None);
+        assert_eq!(extract_json("} backwards {"), None);
+    }
+}
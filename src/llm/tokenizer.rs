@@ -0,0 +1,464 @@
+use super::{ChatRequest, Message, Role};
+use crate::core::NodeError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Counts the tokens a model sees for a text
+pub trait Tokenizer: Send + Sync {
+    /// Returns the number of tokens of `text`
+    fn count(&self, text: &str) -> usize;
+
+    /// Returns the number of tokens of a message, including the tokens framing it
+    fn count_message(&self, message: &Message) -> usize {
+        let calls: usize = message
+            .tool_calls
+            .iter()
+            .map(|call| self.count(&call.name) + self.count(&call.arguments.to_string()))
+            .sum();
+        self.count(&message.content) + calls + 4
+    }
+
+    /// Returns the number of tokens of a conversation, including the tokens priming the reply
+    fn count_messages(&self, messages: &[Message]) -> usize {
+        messages
+            .iter()
+            .map(|m| self.count_message(m))
+            .sum::<usize>()
+            + 3
+    }
+}
+
+/// Estimates tokens at four characters each
+///
+/// Cheap and close enough for English text when the model's vocabulary is not at hand.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenizer;
+
+impl Tokenizer for HeuristicTokenizer {
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// Byte pair encoding tokenizer using a tiktoken vocabulary
+///
+/// Vocabulary files hold one base64 encoded token and its rank per line, as published for
+/// `cl100k_base` and `o200k_base`. Before merging bytes, text is split into words, numbers,
+/// punctuation and whitespace following the `cl100k_base` split pattern of tiktoken.
+#[derive(Clone)]
+pub struct BpeTokenizer {
+    ranks: Arc<HashMap<Vec<u8>, u32>>,
+}
+
+impl BpeTokenizer {
+    /// Loads the vocabulary from a tiktoken file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, NodeError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            NodeError::InvalidTokenizer(format!("cannot read {}: {e}", path.display()))
+        })?;
+        Self::from_tiktoken(&contents)
+    }
+
+    /// Parses a vocabulary in the tiktoken format
+    pub fn from_tiktoken(contents: &str) -> Result<Self, NodeError> {
+        let mut ranks = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |message: &str| {
+                NodeError::InvalidTokenizer(format!("line {}: {message}", number + 1))
+            };
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid("expected a token and its rank"))?;
+            let token = STANDARD
+                .decode(token)
+                .map_err(|_| invalid("token is not valid base64"))?;
+            let rank = rank
+                .trim()
+                .parse()
+                .map_err(|_| invalid("rank is not a number"))?;
+            ranks.insert(token, rank);
+        }
+        if let Some(byte) = (0..=u8::MAX).find(|b| !ranks.contains_key(&vec![*b])) {
+            return Err(NodeError::InvalidTokenizer(format!(
+                "vocabulary has no token for byte {byte:#04x}"
+            )));
+        }
+        Ok(Self {
+            ranks: Arc::new(ranks),
+        })
+    }
+
+    /// Returns the number of tokens in the vocabulary
+    pub fn vocab_size(&self) -> usize {
+        self.ranks.len()
+    }
+
+    /// Returns the ranks of the tokens of `text`
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        pieces(text)
+            .into_iter()
+            .flat_map(|piece| self.merge(piece.as_bytes()))
+            .collect()
+    }
+
+    /// Merges the bytes of a piece into tokens, lowest ranked pair first
+    fn merge(&self, piece: &[u8]) -> Vec<u32> {
+        if let Some(rank) = self.ranks.get(piece) {
+            return vec![*rank];
+        }
+        let mut bounds: Vec<usize> = (0..=piece.len()).collect();
+        while let Some((_, i)) = bounds
+            .windows(3)
+            .enumerate()
+            .filter_map(|(i, w)| self.ranks.get(&piece[w[0]..w[2]]).map(|rank| (*rank, i)))
+            .min()
+        {
+            bounds.remove(i + 1);
+        }
+        bounds
+            .windows(2)
+            .map(|w| self.ranks[&piece[w[0]..w[1]]])
+            .collect()
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+}
+
+impl fmt::Debug for BpeTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BpeTokenizer")
+            .field("vocab_size", &self.vocab_size())
+            .finish()
+    }
+}
+
+/// Splits text into the pieces merged separately, following the tiktoken split pattern
+fn pieces(text: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let offset = |i: usize| chars.get(i).map_or(text.len(), |(offset, _)| *offset);
+    let at = |i: usize| chars.get(i).map(|(_, c)| *c);
+    let is_newline = |c: char| c == '\r' || c == '\n';
+    let is_punct = |c: char| !c.is_whitespace() && !c.is_alphabetic() && !c.is_numeric();
+    let run = |mut i: usize, f: &dyn Fn(char) -> bool| {
+        while at(i).is_some_and(f) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while let Some(c) = at(i) {
+        let next = at(i + 1);
+        let end = if c == '\'' && contraction(&text[offset(i + 1)..]) > 0 {
+            i + 1 + contraction(&text[offset(i + 1)..])
+        } else if c.is_alphabetic() {
+            run(i, &char::is_alphabetic)
+        } else if !c.is_numeric() && !is_newline(c) && next.is_some_and(char::is_alphabetic) {
+            run(i + 1, &char::is_alphabetic)
+        } else if c.is_numeric() {
+            (i + 1..i + 3)
+                .find(|j| !at(*j).is_some_and(char::is_numeric))
+                .unwrap_or(i + 3)
+        } else if is_punct(c) || (c == ' ' && next.is_some_and(is_punct)) {
+            run(run(i + 1, &is_punct), &is_newline)
+        } else {
+            let end = run(i, &char::is_whitespace);
+            match (i..end).rev().find(|j| at(*j).is_some_and(is_newline)) {
+                Some(last_newline) => last_newline + 1,
+                None if end - i > 1 && end < chars.len() => end - 1,
+                None => end,
+            }
+        };
+        pieces.push(&text[offset(i)..offset(end)]);
+        i = end;
+    }
+    pieces
+}
+
+/// Returns the number of characters of an English contraction suffix starting `text`
+fn contraction(text: &str) -> usize {
+    let lower = text.chars().take(2).collect::<String>().to_lowercase();
+    if ["re", "ve", "ll"].contains(&lower.as_str()) {
+        2
+    } else if lower.starts_with(['s', 't', 'm', 'd']) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Keeps requests within the context window of a model
+///
+/// The window has to hold the messages, the tool definitions and the `max_tokens` of the reply.
+/// Requests that do not fit fail with [`NodeError::ContextOverflow`] before they are sent, or
+/// with [`truncating`](ContextBudget::truncating) lose their oldest messages first.
+#[derive(Clone)]
+pub struct ContextBudget {
+    tokenizer: Arc<dyn Tokenizer>,
+    limit: usize,
+    truncate: bool,
+}
+
+impl ContextBudget {
+    /// Creates a budget of `limit` tokens counted with `tokenizer`
+    pub fn new(tokenizer: Arc<dyn Tokenizer>, limit: usize) -> Self {
+        Self {
+            tokenizer,
+            limit,
+            truncate: false,
+        }
+    }
+
+    /// Drops the oldest messages of requests that do not fit
+    ///
+    /// System messages and the last message are always kept, and tool results are dropped
+    /// together with the call they answer.
+    pub fn truncating(mut self) -> Self {
+        self.truncate = true;
+        self
+    }
+
+    /// Returns the size of the context window
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the tokenizer counting the tokens
+    pub fn tokenizer(&self) -> &Arc<dyn Tokenizer> {
+        &self.tokenizer
+    }
+
+    /// Returns the number of tokens `request` takes up, excluding the reply
+    pub fn count(&self, request: &ChatRequest) -> usize {
+        let tools: usize = request
+            .tools
+            .iter()
+            .map(|tool| {
+                self.tokenizer.count(&tool.name)
+                    + self.tokenizer.count(&tool.description)
+                    + self.tokenizer.count(&tool.parameters.to_string())
+            })
+            .sum();
+        self.tokenizer.count_messages(&request.messages) + tools
+    }
+
+    /// Makes `request` fit into the window, failing if it cannot
+    pub fn fit(&self, request: &mut ChatRequest) -> Result<(), NodeError> {
+        let limit = self
+            .limit
+            .saturating_sub(request.max_tokens.unwrap_or(0) as usize);
+        loop {
+            let tokens = self.count(request);
+            if tokens <= limit {
+                return Ok(());
+            }
+            let last = request.messages.len().saturating_sub(1);
+            let oldest = request.messages[..last]
+                .iter()
+                .position(|m| m.role != Role::System)
+                .filter(|_| self.truncate);
+            let Some(oldest) = oldest else {
+                return Err(NodeError::ContextOverflow { tokens, limit });
+            };
+            request.messages.remove(oldest);
+            while oldest + 1 < request.messages.len() && request.messages[oldest].role == Role::Tool
+            {
+                request.messages.remove(oldest);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ContextBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextBudget")
+            .field("limit", &self.limit)
+            .field("truncate", &self.truncate)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ToolCall;
+    use serde_json::json;
+
+    /// A vocabulary of every byte followed by `merges` in rank order
+    fn vocab(merges: &[&str]) -> BpeTokenizer {
+        let bytes = (0..=u8::MAX).map(|b| vec![b]);
+        let tokens = bytes.chain(merges.iter().map(|m| m.as_bytes().to_vec()));
+        let lines: Vec<String> = tokens
+            .enumerate()
+            .map(|(rank, token)| format!("{} {rank}", STANDARD.encode(token)))
+            .collect();
+        BpeTokenizer::from_tiktoken(&lines.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn merges_lowest_ranked_pairs_first() {
+        let bpe = vocab(&["ab", "bc", "abc"]);
+        assert_eq!(bpe.vocab_size(), 259);
+        assert_eq!(bpe.encode("abc"), [258]);
+        assert_eq!(bpe.encode("abcbc"), [258, 257]);
+
+        let bpe = vocab(&["bc", "ab"]);
+        assert_eq!(bpe.encode("abc"), [u32::from(b'a'), 256]);
+    }
+
+    #[test]
+    fn falls_back_to_bytes() {
+        let bpe = vocab(&["ab"]);
+        assert_eq!(bpe.encode("\u{1f600}").len(), 4);
+        assert_eq!(bpe.count("x\u{e9}\r\n\t ab"), 8);
+        assert_eq!(bpe.encode(""), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn rejects_invalid_vocabularies() {
+        let err = BpeTokenizer::from_tiktoken("YQ== 0").unwrap_err();
+        assert!(err.to_string().contains("no token for byte 0x00"), "{err}");
+        let err = BpeTokenizer::from_tiktoken("YQ==").unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{err}");
+        let err = BpeTokenizer::from_tiktoken("\n!!! 1").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("line 2: token is not valid base64"),
+            "{err}"
+        );
+        let err = BpeTokenizer::from_tiktoken("YQ== x").unwrap_err();
+        assert!(err.to_string().contains("rank is not a number"), "{err}");
+    }
+
+    #[test]
+    fn splits_contractions() {
+        assert_eq!(
+            pieces("I'm sure you'll"),
+            ["I", "'m", " sure", " you", "'ll"]
+        );
+        assert_eq!(pieces("WE'RE it's"), ["WE", "'RE", " it", "'s"]);
+        assert_eq!(pieces("'x"), ["'x"]);
+    }
+
+    #[test]
+    fn splits_digits_into_runs_of_three() {
+        assert_eq!(pieces("1234567"), ["123", "456", "7"]);
+        assert_eq!(pieces("a12b"), ["a", "12", "b"]);
+        assert_eq!(pieces("x 2024"), ["x", " ", "202", "4"]);
+    }
+
+    #[test]
+    fn splits_punctuation_newlines_and_whitespace() {
+        assert_eq!(
+            pieces("Hello, world!\n\nNext"),
+            ["Hello", ",", " world", "!\n\n", "Next"]
+        );
+        assert_eq!(pieces("a  b"), ["a", " ", " b"]);
+        assert_eq!(pieces("x\n  y"), ["x", "\n", " ", " y"]);
+        assert_eq!(pieces("end  "), ["end", "  "]);
+        assert_eq!(pieces(" ...ok"), [" ...", "ok"]);
+        assert_eq!(pieces("\u{e9}t\u{e9}"), ["\u{e9}t\u{e9}"]);
+    }
+
+    /// Counts one token per word, so budgets are easy to work out
+    struct Words;
+
+    impl Tokenizer for Words {
+        fn count(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    /// Returns a conversation of 35 tokens when counted with [`Words`]
+    fn conversation() -> ChatRequest {
+        let call = ToolCall {
+            id: "1".to_string(),
+            name: "t".to_string(),
+            arguments: json!({}),
+        };
+        ChatRequest::new(vec![
+            Message::system("s"),
+            Message::user("a a a a a a"),
+            Message::assistant("").with_tool_calls(vec![call]),
+            Message::tool("1", "r r"),
+            Message::user("last"),
+        ])
+    }
+
+    fn roles(request: &ChatRequest) -> Vec<Role> {
+        request.messages.iter().map(|m| m.role).collect()
+    }
+
+    #[test]
+    fn counts_messages_and_tools() {
+        let budget = ContextBudget::new(Arc::new(Words), 100);
+        assert_eq!(budget.count(&conversation()), 35);
+        let tool = crate::llm::ToolDefinition {
+            name: "t".to_string(),
+            description: "does things".to_string(),
+            parameters: json!({}),
+        };
+        assert_eq!(budget.count(&conversation().with_tools(vec![tool])), 39);
+    }
+
+    #[test]
+    fn truncating_drops_oldest_messages_and_their_tool_results() {
+        let budget = ContextBudget::new(Arc::new(Words), 30).truncating();
+        let mut request = conversation();
+        budget.fit(&mut request).unwrap();
+        assert_eq!(
+            roles(&request),
+            [Role::System, Role::Assistant, Role::Tool, Role::User]
+        );
+
+        let budget = ContextBudget::new(Arc::new(Words), 20).truncating();
+        let mut request = conversation();
+        budget.fit(&mut request).unwrap();
+        assert_eq!(roles(&request), [Role::System, Role::User]);
+        assert_eq!(request.messages[1].content, "last");
+    }
+
+    #[test]
+    fn keeps_system_and_last_message_even_if_they_overflow() {
+        let budget = ContextBudget::new(Arc::new(Words), 10).truncating();
+        let mut request = conversation();
+        let err = budget.fit(&mut request).unwrap_err();
+        assert!(matches!(
+            err,
+            NodeError::ContextOverflow {
+                tokens: 13,
+                limit: 10
+            }
+        ));
+        assert_eq!(roles(&request), [Role::System, Role::User]);
+    }
+
+    #[test]
+    fn reserves_room_for_the_reply() {
+        let budget = ContextBudget::new(Arc::new(Words), 35);
+        budget.fit(&mut conversation()).unwrap();
+        let mut request = conversation().with_max_tokens(1);
+        let err = budget.fit(&mut request).unwrap_err();
+        assert!(matches!(
+            err,
+            NodeError::ContextOverflow {
+                tokens: 35,
+                limit: 34
+            }
+        ));
+        assert_eq!(request.messages.len(), 5);
+    }
+}
@@ -0,0 +1,357 @@
+use super::node::{from_value, to_value};
+use super::{ChatResponse, LlmNode, Message, Role, ToolCall, ToolDefinition, schema};
+use crate::core::{AsyncNodeLogic, ExecContext, Node, NodeError, NodeOutput};
+use crate::shared::SharedData;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Action returned by a [`ToolNode`] after the model called tools
+pub const TOOL_CALLS_ACTION: &str = "tool_calls";
+
+/// A function the model can call
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// Returns the name the model calls the tool by
+    fn name(&self) -> &str;
+
+    /// Returns what the tool does, telling the model when to call it
+    fn description(&self) -> &str;
+
+    /// Returns the JSON schema of the arguments
+    fn parameters(&self) -> Value;
+
+    /// Runs the tool with arguments matching its parameters
+    async fn invoke(&self, arguments: Value) -> Result<Value, NodeError>;
+}
+
+/// The tools available to a model, by name
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tool, replacing any tool of the same name
+    pub fn register(&mut self, tool: impl Tool + 'static) -> &mut Self {
+        self.tools.insert(tool.name().to_string(), Arc::new(tool));
+        self
+    }
+
+    /// Returns the tool with the given name
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Tool>> {
+        self.tools.get(name)
+    }
+
+    /// Returns true if a tool with the given name is registered
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name)
+    }
+
+    /// Returns the number of registered tools
+    pub fn len(&self) -> usize {
+        self.tools.len()
+    }
+
+    /// Returns true if no tool is registered
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Returns the descriptions of the tools sent to the model
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools
+            .values()
+            .map(|tool| ToolDefinition {
+                name: tool.name().to_string(),
+                description: tool.description().to_string(),
+                parameters: tool.parameters(),
+            })
+            .collect()
+    }
+
+    /// Runs the tool requested by `call` after checking its arguments
+    pub async fn call(&self, call: &ToolCall) -> Result<Value, NodeError> {
+        let tool = self
+            .get(&call.name)
+            .ok_or_else(|| NodeError::UnknownTool(call.name.clone()))?;
+        schema::validate(&tool.parameters(), &call.arguments).map_err(|message| {
+            NodeError::InvalidToolArguments {
+                tool: call.name.clone(),
+                message,
+            }
+        })?;
+        tool.invoke(call.arguments.clone()).await
+    }
+
+    /// Runs the tool requested by `call` and returns the message reporting its result
+    ///
+    /// Failures are reported in the message, so the model can correct the call.
+    pub async fn respond(&self, call: &ToolCall) -> Message {
+        let content = match self.call(call).await {
+            Ok(Value::String(text)) => text,
+            Ok(value) => value.to_string(),
+            Err(e) => format!("Error: {e}"),
+        };
+        Message::tool(&call.id, content)
+    }
+}
+
+impl fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.tools.keys()).finish()
+    }
+}
+
+/// Logic letting a language model call tools
+///
+/// Each run sends the conversation to the model along with the tools. If the model calls tools,
+/// they are run, their results are added to the conversation and the node returns
+/// [`TOOL_CALLS_ACTION`], so connecting that action back to the node lets the model continue
+/// until it replies with text. The reply is then stored under the output key of the wrapped
+/// [`LlmNode`] and the node returns the default action.
+///
+/// The conversation is kept in the shared data under the conversation key. It starts from the
+/// prompt of the [`LlmNode`], after its memory if it has one, when the key is not set yet. Once
+/// the model has replied with text, the next run continues the conversation with the prompt.
+#[derive(Clone)]
+pub struct ToolNode {
+    llm: LlmNode,
+    tools: Arc<ToolRegistry>,
+    conversation_key: String,
+}
+
+/// The outcome of one exchange with the model
+#[derive(Serialize, Deserialize)]
+struct Turn {
+    response: ChatResponse,
+    results: Vec<Message>,
+}
+
+impl ToolNode {
+    /// Creates logic letting the model configured in `llm` call `tools`
+    ///
+    /// # Arguments
+    ///
+    /// * `llm` - The model and its prompt
+    /// * `tools` - The tools the model may call
+    /// * `conversation_key` - The shared data key the conversation is kept under
+    pub fn new(llm: LlmNode, tools: ToolRegistry, conversation_key: &str) -> Self {
+        Self {
+            llm,
+            tools: Arc::new(tools),
+            conversation_key: conversation_key.to_string(),
+        }
+    }
+
+    /// Returns the tools the model may call
+    pub fn tools(&self) -> &ToolRegistry {
+        &self.tools
+    }
+
+    /// Returns the shared data key the conversation is kept under
+    pub fn conversation_key(&self) -> &str {
+        &self.conversation_key
+    }
+
+    /// Creates a node with the given name running this logic
+    pub fn into_node(self, name: &str) -> Result<Node, NodeError> {
+        Ok(Node::new(Some(name))?.with_async_logic(self))
+    }
+}
+
+/// Returns true if `conversation` ends with a reply of the model that called no tools
+fn answered(conversation: &[Message]) -> bool {
+    conversation
+        .last()
+        .is_some_and(|message| message.role == Role::Assistant && message.tool_calls.is_empty())
+}
+
+#[async_trait]
+impl AsyncNodeLogic for ToolNode {
+    async fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        let conversation = match shared.get::<Vec<Message>>(&self.conversation_key)? {
+            Some(mut conversation) if answered(&conversation) => {
+                conversation.extend(self.llm.prompt(shared)?);
+                conversation
+            }
+            Some(conversation) => conversation,
+            None => self.llm.conversation(shared)?,
+        };
+        to_value(&conversation)
+    }
+
+    async fn exec(&self, prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+        let request = self
+            .llm
+            .request_for(from_value(prep_res)?)
+            .with_tools(self.tools.definitions());
+        let response = self.llm.complete(request, ctx).await?;
+        let mut results = Vec::new();
+        for call in &response.tool_calls {
+            results.push(self.tools.respond(call).await);
+        }
+        to_value(&Turn { response, results })
+    }
+
+    async fn post(
+        &self,
+        shared: &SharedData,
+        prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        let mut conversation: Vec<Message> = from_value(&prep_res)?;
+        let Turn { response, results } = from_value(&exec_res)?;
+        let called_tools = !response.tool_calls.is_empty();
+        conversation.push(
+            Message::assistant(response.content.clone()).with_tool_calls(response.tool_calls),
+        );
+        conversation.extend(results);
+        shared.set(self.conversation_key.clone(), conversation)?;
+
+        if called_tools {
+            return Ok(NodeOutput::Action(TOOL_CALLS_ACTION.to_string()));
+        }
+        self.llm.remember(shared, &response.content)?;
+        shared.set_value(self.llm.output_key(), response.content);
+        Ok(NodeOutput::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::scripted::{Scripted, calling};
+    use serde_json::json;
+
+    /// Adds two integers
+    struct Add;
+
+    #[async_trait]
+    impl Tool for Add {
+        fn name(&self) -> &str {
+            "add"
+        }
+
+        fn description(&self) -> &str {
+            "Adds two integers"
+        }
+
+        fn parameters(&self) -> Value {
+            json!({
+                "type": "object",
+                "properties": { "a": { "type": "integer" }, "b": { "type": "integer" } },
+                "required": ["a", "b"]
+            })
+        }
+
+        async fn invoke(&self, arguments: Value) -> Result<Value, NodeError> {
+            let operand = |name: &str| arguments[name].as_i64().unwrap_or_default();
+            Ok(json!(operand("a") + operand("b")))
+        }
+    }
+
+    fn registry() -> ToolRegistry {
+        let mut tools = ToolRegistry::new();
+        tools.register(Add);
+        tools
+    }
+
+    fn call(name: &str, arguments: Value) -> ToolCall {
+        calling(name, arguments).tool_calls.remove(0)
+    }
+
+    #[tokio::test]
+    async fn calls_registered_tools_with_valid_arguments() {
+        let tools = registry();
+        let result = tools.call(&call("add", json!({ "a": 2, "b": 3 }))).await;
+        assert_eq!(result.unwrap(), json!(5));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_tools_and_invalid_arguments() {
+        let tools = registry();
+        let err = tools.call(&call("sub", json!({}))).await.unwrap_err();
+        assert!(matches!(err, NodeError::UnknownTool(name) if name == "sub"));
+
+        let err = tools.call(&call("add", json!({ "a": "2" }))).await;
+        assert!(matches!(
+            err.unwrap_err(),
+            NodeError::InvalidToolArguments { tool, .. } if tool == "add"
+        ));
+    }
+
+    #[tokio::test]
+    async fn responds_with_errors_as_tool_messages() {
+        let message = registry().respond(&call("sub", json!({}))).await;
+        assert_eq!(message.role, Role::Tool);
+        assert_eq!(message.tool_call_id.as_deref(), Some("call_sub"));
+        assert!(
+            message.content.starts_with("Error: "),
+            "{}",
+            message.content
+        );
+    }
+
+    #[tokio::test]
+    async fn continues_until_the_model_replies_with_text() {
+        let client = Scripted::new([
+            calling("add", json!({ "a": 2, "b": 3 })),
+            ChatResponse::new("5"),
+        ]);
+        let node = ToolNode::new(
+            LlmNode::new(client.clone(), "q", "answer"),
+            registry(),
+            "chat",
+        )
+        .into_node("tools")
+        .unwrap();
+        let shared = SharedData::new();
+        shared.set("q", "What is 2 + 3?").unwrap();
+
+        let output = node.exec_async(&shared).await.unwrap();
+        assert_eq!(output.action(), TOOL_CALLS_ACTION);
+        assert!(!shared.contains_key("answer"));
+        let output = node.exec_async(&shared).await.unwrap();
+        assert_eq!(output.action(), NodeOutput::default().action());
+        assert_eq!(shared.get_value("answer"), Some(json!("5")));
+
+        let requests = client.requests();
+        assert_eq!(requests[0].tools.len(), 1);
+        let last = &requests[1].messages[2];
+        assert_eq!((last.role, last.content.as_str()), (Role::Tool, "5"));
+    }
+
+    #[tokio::test]
+    async fn asks_the_next_prompt_after_an_answer() {
+        let client = Scripted::new([ChatResponse::new("first"), ChatResponse::new("second")]);
+        let node = ToolNode::new(
+            LlmNode::new(client.clone(), "q", "answer"),
+            registry(),
+            "chat",
+        )
+        .into_node("tools")
+        .unwrap();
+        let shared = SharedData::new();
+        shared.set("q", "first question").unwrap();
+        node.exec_async(&shared).await.unwrap();
+        shared.set("q", "second question").unwrap();
+        node.exec_async(&shared).await.unwrap();
+
+        let contents: Vec<_> = client.requests()[1]
+            .messages
+            .iter()
+            .map(|message| message.content.clone())
+            .collect();
+        assert_eq!(contents, ["first question", "first", "second question"]);
+        assert_eq!(shared.get_value("answer"), Some(json!("second")));
+    }
+}
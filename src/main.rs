@@ -0,0 +1,64 @@
+use llmflow::prelude::*;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Upper-cases the text stored under `input`
+struct Shout;
+
+impl NodeLogic for Shout {
+    fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        shared.require("input")
+    }
+
+    fn exec(&self, prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+        let text = prep_res
+            .as_str()
+            .ok_or_else(|| NodeError::ExecutionError("input must be a string".to_string()))?;
+        Ok(Value::from(text.to_uppercase()))
+    }
+
+    fn post(
+        &self,
+        shared: &SharedData,
+        _prep_res: Value,
+        exec_res: Value,
+    ) -> Result<NodeOutput, NodeError> {
+        shared.set_value("output", exec_res);
+        Ok(NodeOutput::default())
+    }
+}
+
+/// Prints the text stored under `output`
+struct Print;
+
+impl NodeLogic for Print {
+    fn prep(&self, shared: &SharedData) -> Result<Value, NodeError> {
+        shared.require("output")
+    }
+
+    fn exec(&self, prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+        println!("{prep_res}");
+        Ok(Value::Null)
+    }
+}
+
+fn main() -> Result<(), NodeError> {
+    let print = Node::new(Some("print"))?.with_logic(Print);
+    let shout = Node::new(Some("shout"))?
+        .with_logic(Shout)
+        .with_retries(2)?
+        .with_next(Arc::new(print));
+
+    let mut flow = Flow::new();
+    flow.start(shout);
+
+    let shared = SharedData::new();
+    shared.set("input", "hello, flow")?;
+
+    let result = flow.run(shared)?;
+    println!(
+        "Finished at {} after {} steps",
+        result.last_node, result.steps
+    );
+    Ok(())
+}
@@ -0,0 +1,514 @@
+//! Prompt templates
+//!
+//! A [`PromptTemplate`] renders text from the shared data, so nodes do not have to format prompts
+//! by hand. Templates use a small subset of the Handlebars syntax:
+//!
+//! ```text
+//! Answer the question of {{user.name}}: {{user_query}}
+//! {{#if documents}}Use these documents:
+//! {{#each documents}}{{@index}}. {{this.title}}
+//! {{/each}}{{else}}Answer from memory.{{/if}}
+//! ```
+//!
+//! * `{{key}}` inserts a value, with `.` reaching into objects and lists (`{{items.0.id}}`)
+//! * `{{#each key}}...{{/each}}` repeats its body for every item of a list, where `{{this}}` is
+//!   the item, `{{@index}}` its position and other names also look into the item's fields
+//! * `{{#if key}}...{{else}}...{{/if}}` renders its body if the value is present and not `null`,
+//!   `false`, `0` or empty; `{{#each}}` may also have an `{{else}}` for empty lists
+//!
+//! Syntax errors are reported by [`PromptTemplate::parse`] as [`NodeError::InvalidTemplate`], a
+//! value missing while rendering as [`NodeError::MissingTemplateVariable`].
+use crate::core::NodeError;
+use crate::shared::SharedData;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed prompt template
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Value(Path),
+    Each {
+        path: Path,
+        body: Vec<Segment>,
+        otherwise: Vec<Segment>,
+    },
+    If {
+        path: Path,
+        body: Vec<Segment>,
+        otherwise: Vec<Segment>,
+    },
+}
+
+/// A reference to a value, such as `user.name` or `this.title`
+#[derive(Debug, Clone, PartialEq)]
+struct Path {
+    root: Root,
+    fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Root {
+    Name(String),
+    This,
+    Index,
+}
+
+/// The current item of each enclosing `{{#each}}`
+struct Scope<'a> {
+    item: &'a Value,
+    index: usize,
+}
+
+/// What ended a sequence of segments
+enum End {
+    Eof,
+    Else,
+    Close(String),
+}
+
+impl PromptTemplate {
+    /// Parses a template, failing on syntax errors
+    pub fn parse(source: &str) -> Result<Self, NodeError> {
+        let mut parser = Parser {
+            rest: source,
+            depth: 0,
+        };
+        let (segments, end) = parser.segments()?;
+        match end {
+            End::Eof => Ok(Self {
+                source: source.to_string(),
+                segments,
+            }),
+            End::Else => Err(invalid("{{else}} outside of a block")),
+            End::Close(kind) => Err(invalid(format!("unexpected {{{{/{kind}}}}}"))),
+        }
+    }
+
+    /// Returns the text the template was parsed from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the shared data keys the template reads, in order of first use
+    ///
+    /// Names inside `{{#each}}` bodies are left out, as they may be fields of the items, and so are
+    /// keys only tested by `{{#if}}`, as they may be missing. Keys read in the branches of an
+    /// `{{#if}}` are included even though only one branch is rendered.
+    pub fn variables(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        collect_variables(&self.segments, &mut names);
+        names
+    }
+
+    /// Renders the template with the values in the shared data
+    pub fn render(&self, shared: &SharedData) -> Result<String, NodeError> {
+        let values = shared.snapshot();
+        let mut out = String::new();
+        render(&self.segments, &values, &mut Vec::new(), &mut out)?;
+        Ok(out)
+    }
+}
+
+impl FromStr for PromptTemplate {
+    type Err = NodeError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        Self::parse(source)
+    }
+}
+
+impl fmt::Display for PromptTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.root {
+            Root::Name(name) => f.write_str(name)?,
+            Root::This => f.write_str("this")?,
+            Root::Index => f.write_str("@index")?,
+        }
+        for field in &self.fields {
+            write!(f, ".{field}")?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: impl Into<String>) -> NodeError {
+    NodeError::InvalidTemplate(message.into())
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    /// Parses segments up to the end of the input or of the enclosing block
+    fn segments(&mut self) -> Result<(Vec<Segment>, End), NodeError> {
+        let mut segments = Vec::new();
+        loop {
+            let Some(open) = self.rest.find("{{") else {
+                if !self.rest.is_empty() {
+                    segments.push(Segment::Text(self.rest.to_string()));
+                }
+                self.rest = "";
+                return Ok((segments, End::Eof));
+            };
+            if open > 0 {
+                segments.push(Segment::Text(self.rest[..open].to_string()));
+            }
+            let after = &self.rest[open + 2..];
+            let close = after
+                .find("}}")
+                .ok_or_else(|| invalid(format!("unclosed tag '{{{{{}'", after.trim())))?;
+            let tag = after[..close].trim();
+            self.rest = &after[close + 2..];
+
+            if tag == "else" {
+                return Ok((segments, End::Else));
+            } else if let Some(kind) = tag.strip_prefix('/') {
+                return Ok((segments, End::Close(kind.trim().to_string())));
+            } else if let Some(block) = tag.strip_prefix('#') {
+                segments.push(self.block(block)?);
+            } else {
+                segments.push(Segment::Value(self.path(tag)?));
+            }
+        }
+    }
+
+    /// Parses the body of a `{{#each}}` or `{{#if}}` block
+    fn block(&mut self, tag: &str) -> Result<Segment, NodeError> {
+        let (kind, path) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        if kind != "each" && kind != "if" {
+            return Err(invalid(format!("unknown block '{{{{#{kind}}}}}'")));
+        }
+        let path = self.path(path.trim())?;
+        let opening = format!("{{{{#{kind} {path}}}}}");
+
+        self.depth += usize::from(kind == "each");
+        let body = self.segments();
+        self.depth -= usize::from(kind == "each");
+        let (body, mut end) = body?;
+        let mut otherwise = Vec::new();
+        if let End::Else = end {
+            (otherwise, end) = self.segments()?;
+        }
+
+        match end {
+            End::Close(closing) if closing == kind => {}
+            End::Close(closing) => {
+                return Err(invalid(format!("{opening} closed by {{{{/{closing}}}}}")));
+            }
+            End::Else => return Err(invalid(format!("{opening} has more than one {{{{else}}}}"))),
+            End::Eof => return Err(invalid(format!("{opening} is never closed"))),
+        }
+        Ok(if kind == "each" {
+            Segment::Each {
+                path,
+                body,
+                otherwise,
+            }
+        } else {
+            Segment::If {
+                path,
+                body,
+                otherwise,
+            }
+        })
+    }
+
+    /// Parses a reference to a value
+    fn path(&self, tag: &str) -> Result<Path, NodeError> {
+        let mut parts = tag.split('.');
+        let first = parts.next().unwrap_or_default();
+        let fields: Vec<String> = parts.map(str::to_string).collect();
+        let is_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        };
+
+        let root = match first {
+            "this" | "@index" if self.depth == 0 => {
+                return Err(invalid(format!("'{{{{{tag}}}}}' outside of {{{{#each}}}}")));
+            }
+            "this" => Root::This,
+            "@index" if fields.is_empty() => Root::Index,
+            name if is_name(name) => Root::Name(name.to_string()),
+            _ => return Err(invalid(format!("invalid variable name '{tag}'"))),
+        };
+        if !fields.iter().all(|field| is_name(field)) {
+            return Err(invalid(format!("invalid variable name '{tag}'")));
+        }
+        Ok(Path { root, fields })
+    }
+}
+
+fn collect_variables(segments: &[Segment], names: &mut Vec<String>) {
+    for segment in segments {
+        let (path, blocks): (Option<&Path>, &[&[Segment]]) = match segment {
+            Segment::Text(_) => continue,
+            Segment::Value(path) => (Some(path), &[]),
+            Segment::Each {
+                path, otherwise, ..
+            } => (Some(path), &[otherwise]),
+            Segment::If {
+                body, otherwise, ..
+            } => (None, &[body, otherwise]),
+        };
+        if let Some(Path {
+            root: Root::Name(name),
+            ..
+        }) = path
+            && !names.contains(name)
+        {
+            names.push(name.clone());
+        }
+        for block in blocks {
+            collect_variables(block, names);
+        }
+    }
+}
+
+/// Looks up the value a path refers to, if present
+fn lookup<'a>(
+    path: &Path,
+    values: &'a HashMap<String, Value>,
+    scopes: &[Scope<'a>],
+) -> Option<Cow<'a, Value>> {
+    let mut value = match &path.root {
+        Root::This => scopes.last()?.item,
+        Root::Index => return Some(Cow::Owned(Value::from(scopes.last()?.index))),
+        Root::Name(name) => scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.item.get(name))
+            .or_else(|| values.get(name))?,
+    };
+    for field in &path.fields {
+        value = match value {
+            Value::Array(items) => items.get(field.parse::<usize>().ok()?)?,
+            _ => value.get(field)?,
+        };
+    }
+    Some(Cow::Borrowed(value))
+}
+
+fn is_truthy(value: Option<Cow<'_, Value>>) -> bool {
+    let value = value.as_deref();
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(fields)) => !fields.is_empty(),
+    }
+}
+
+fn render<'a>(
+    segments: &'a [Segment],
+    values: &'a HashMap<String, Value>,
+    scopes: &mut Vec<Scope<'a>>,
+    out: &mut String,
+) -> Result<(), NodeError> {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Value(path) => match lookup(path, values, scopes).as_deref() {
+                Some(Value::String(s)) => out.push_str(s),
+                Some(Value::Null) => {}
+                Some(value) => out.push_str(&value.to_string()),
+                None => return Err(NodeError::MissingTemplateVariable(path.to_string())),
+            },
+            Segment::If {
+                path,
+                body,
+                otherwise,
+            } => {
+                let value = lookup(path, values, scopes);
+                let block = if is_truthy(value) { body } else { otherwise };
+                render(block, values, scopes, out)?;
+            }
+            Segment::Each {
+                path,
+                body,
+                otherwise,
+            } => {
+                let items: &[Value] = match lookup(path, values, scopes) {
+                    Some(Cow::Borrowed(Value::Array(items))) => items,
+                    Some(Cow::Borrowed(Value::Null)) => &[],
+                    Some(_) => {
+                        return Err(NodeError::InvalidSharedData {
+                            key: path.to_string(),
+                            message: "expected a list to iterate over".into(),
+                        });
+                    }
+                    None => return Err(NodeError::MissingTemplateVariable(path.to_string())),
+                };
+                if items.is_empty() {
+                    render(otherwise, values, scopes, out)?;
+                }
+                for (index, item) in items.iter().enumerate() {
+                    scopes.push(Scope { item, index });
+                    let rendered = render(body, values, scopes, out);
+                    scopes.pop();
+                    rendered?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, values: Value) -> Result<String, NodeError> {
+        let shared = SharedData::new();
+        for (key, value) in values.as_object().unwrap() {
+            shared.set_value(key.clone(), value.clone());
+        }
+        PromptTemplate::parse(source)?.render(&shared)
+    }
+
+    fn parse_error(source: &str) -> String {
+        match PromptTemplate::parse(source) {
+            Err(NodeError::InvalidTemplate(message)) => message,
+            other => panic!("expected a syntax error for {source:?}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn renders_values_and_paths() {
+        let values = json!({ "user": { "name": "Ada", "tags": ["x", "y"] }, "n": 3, "none": null });
+        assert_eq!(
+            render("{{ user.name }} {{user.tags.1}} {{n}}{{none}}!", values).unwrap(),
+            "Ada y 3!"
+        );
+    }
+
+    #[test]
+    fn renders_nested_blocks() {
+        let source = "{{#each groups}}{{@index}}:{{name}}[\
+            {{#each items}}{{#if done}}+{{else}}-{{/if}}{{this.id}}{{else}}empty{{/each}}]\
+            {{/each}}{{#if footer}}{{footer}}{{else}}no footer{{/if}}";
+        let values = json!({ "groups": [
+            { "name": "a", "items": [{ "id": 1, "done": true }, { "id": 2, "done": false }] },
+            { "name": "b", "items": [] },
+        ] });
+        assert_eq!(
+            render(source, values).unwrap(),
+            "0:a[+1-2]1:b[empty]no footer"
+        );
+    }
+
+    #[test]
+    fn if_treats_empty_values_as_false() {
+        let source = "{{#if v}}yes{{else}}no{{/if}}";
+        for value in [
+            json!(null),
+            json!(false),
+            json!(0),
+            json!(""),
+            json!([]),
+            json!({}),
+        ] {
+            assert_eq!(render(source, json!({ "v": value })).unwrap(), "no");
+        }
+        for value in [
+            json!(true),
+            json!(1),
+            json!("x"),
+            json!([0]),
+            json!({ "a": 1 }),
+        ] {
+            assert_eq!(render(source, json!({ "v": value })).unwrap(), "yes");
+        }
+        assert_eq!(render(source, json!({})).unwrap(), "no");
+    }
+
+    #[test]
+    fn inner_scopes_shadow_outer_ones() {
+        let source = "{{name}}:{{#each items}}{{name}}{{#each items}}({{name}}@{{@index}}){{/each}}\
+            {{title}};{{/each}}";
+        let values = json!({
+            "name": "root",
+            "title": "t",
+            "items": [
+                { "name": "a", "items": [{ "name": "a1" }, { "other": 1 }] },
+                { "items": [] },
+            ],
+        });
+        assert_eq!(render(source, values).unwrap(), "root:a(a1@0)(a@1)t;roott;");
+    }
+
+    #[test]
+    fn this_and_index_are_rejected_outside_each() {
+        assert!(parse_error("{{this}}").contains("outside"));
+        assert!(parse_error("{{@index}}").contains("outside"));
+        assert!(parse_error("{{#if this}}x{{/if}}").contains("outside"));
+        assert!(parse_error("{{#each items}}{{/each}}{{this.name}}").contains("outside"));
+    }
+
+    #[test]
+    fn rejects_unclosed_and_mismatched_blocks() {
+        assert!(parse_error("{{#each items}}x").contains("never closed"));
+        assert!(parse_error("{{#if a}}x{{/each}}").contains("closed by"));
+        assert!(parse_error("{{#if a}}x{{else}}y{{else}}z{{/if}}").contains("more than one"));
+        assert!(parse_error("x{{/if}}").contains("unexpected"));
+        assert!(parse_error("x{{else}}y").contains("outside of a block"));
+        assert!(parse_error("{{#with a}}x{{/with}}").contains("unknown block"));
+        assert!(parse_error("{{name").contains("unclosed tag"));
+        assert!(parse_error("{{a b}}").contains("invalid variable"));
+        assert!(parse_error("{{a..b}}").contains("invalid variable"));
+    }
+
+    #[test]
+    fn reports_missing_variables_and_non_lists() {
+        assert!(matches!(
+            render("{{user.name}}", json!({ "user": {} })),
+            Err(NodeError::MissingTemplateVariable(path)) if path == "user.name"
+        ));
+        assert!(matches!(
+            render("{{#each items}}{{this.id}}{{/each}}", json!({ "items": [{}] })),
+            Err(NodeError::MissingTemplateVariable(path)) if path == "this.id"
+        ));
+        assert!(matches!(
+            render("{{#each items}}x{{/each}}", json!({})),
+            Err(NodeError::MissingTemplateVariable(path)) if path == "items"
+        ));
+        assert!(matches!(
+            render("{{#each items}}x{{/each}}", json!({ "items": "abc" })),
+            Err(NodeError::InvalidSharedData { key, .. }) if key == "items"
+        ));
+    }
+
+    #[test]
+    fn lists_variables_in_order() {
+        let template = PromptTemplate::parse(
+            "{{b}} {{a.x}} {{#if c}}{{d}}{{else}}{{e}}{{/if}}\
+             {{#each items}}{{f}}{{else}}{{g}}{{/each}}{{b}}",
+        )
+        .unwrap();
+        assert_eq!(template.variables(), ["b", "a", "d", "e", "items", "g"]);
+        assert_eq!(template.to_string(), template.source());
+    }
+}
@@ -0,0 +1,327 @@
+//! Retry policies for node execution
+//!
+//! A [`RetryPolicy`] decides how many times a node's `exec` is retried, how long to wait between
+//! attempts and which errors are worth retrying at all.
+use crate::core::{MAX_RETRIES, MAX_WAIT_SECONDS, NodeError};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How the wait between attempts grows
+///
+/// Every delay is capped at 60 seconds, including delays that would overflow a [`Duration`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backoff {
+    /// Waits the same time before every retry
+    Constant(Duration),
+    /// Waits `initial`, then adds `increment` for every further retry
+    Linear {
+        initial: Duration,
+        increment: Duration,
+    },
+    /// Waits `initial`, then multiplies the wait by `multiplier` up to `max`
+    Exponential {
+        initial: Duration,
+        multiplier: f64,
+        max: Duration,
+    },
+    /// Waits a random time between `base` and three times the previous wait, up to `cap`
+    DecorrelatedJitter { base: Duration, cap: Duration },
+}
+
+impl fmt::Display for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backoff::Constant(wait) => write!(f, "{wait:?}"),
+            Backoff::Linear { initial, increment } => write!(f, "{initial:?} +{increment:?}"),
+            Backoff::Exponential {
+                initial,
+                multiplier,
+                max,
+            } => write!(f, "{initial:?} x{multiplier} up to {max:?}"),
+            Backoff::DecorrelatedJitter { base, cap } => write!(f, "jitter {base:?} to {cap:?}"),
+        }
+    }
+}
+
+type RetryPredicate = Arc<dyn Fn(&NodeError) -> bool + Send + Sync>;
+
+/// Controls how a node retries a failed `exec`
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_retries: u8,
+    backoff: Backoff,
+    max_elapsed: Option<Duration>,
+    retry_if: RetryPredicate,
+}
+
+impl RetryPolicy {
+    /// Creates a policy that retries up to `max_retries` times without waiting
+    pub fn new(max_retries: u8) -> Result<Self, NodeError> {
+        if max_retries > MAX_RETRIES {
+            return Err(NodeError::InvalidRetryCount(max_retries, MAX_RETRIES));
+        }
+        Ok(Self {
+            max_retries,
+            ..Self::default()
+        })
+    }
+
+    /// Sets how the wait between attempts grows
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Stops retrying once the next attempt would start after `max_elapsed` since the first one
+    pub fn with_max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+
+    /// Only retries errors for which `predicate` returns true
+    ///
+    /// By default every error for which [`NodeError::is_retryable`] is true is retried.
+    pub fn with_retry_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&NodeError) -> bool + Send + Sync + 'static,
+    {
+        self.retry_if = Arc::new(predicate);
+        self
+    }
+
+    /// Returns the max number of retries
+    pub fn max_retries(&self) -> u8 {
+        self.max_retries
+    }
+
+    /// Returns how the wait between attempts grows
+    pub fn backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// Returns the max time spent retrying, if any
+    pub fn max_elapsed(&self) -> Option<Duration> {
+        self.max_elapsed
+    }
+
+    /// Returns true if the policy allows `error` to be retried
+    pub fn should_retry(&self, error: &NodeError) -> bool {
+        (self.retry_if)(error)
+    }
+
+    pub(crate) fn set_max_retries(&mut self, max_retries: u8) {
+        self.max_retries = max_retries;
+    }
+
+    /// Starts tracking the attempts of one execution
+    pub(crate) fn schedule(&self) -> RetrySchedule<'_> {
+        RetrySchedule {
+            policy: self,
+            attempts: 0,
+            started: Instant::now(),
+            previous: Duration::ZERO,
+            rng: seed(),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: Backoff::Constant(Duration::ZERO),
+            max_elapsed: None,
+            retry_if: Arc::new(NodeError::is_retryable),
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("backoff", &self.backoff)
+            .field("max_elapsed", &self.max_elapsed)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The retry state of a single node execution
+pub(crate) struct RetrySchedule<'a> {
+    policy: &'a RetryPolicy,
+    attempts: u8,
+    started: Instant,
+    previous: Duration,
+    rng: u64,
+}
+
+impl RetrySchedule<'_> {
+    /// Returns the number of retries made so far
+    pub(crate) fn attempts(&self) -> u8 {
+        self.attempts
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one
+    ///
+    /// Returns `None` if the attempt should not be retried.
+    pub(crate) fn next_delay(&mut self, error: &NodeError) -> Option<Duration> {
+        if !self.policy.should_retry(error) {
+            return None;
+        }
+        let delay = self.delay();
+        let out_of_time = self
+            .policy
+            .max_elapsed
+            .is_some_and(|max| self.started.elapsed() + delay > max);
+        if self.attempts >= self.policy.max_retries || out_of_time {
+            return None;
+        }
+        self.attempts += 1;
+        self.previous = delay;
+        Some(delay)
+    }
+
+    /// Returns the error to report once `error` is no longer retried
+    ///
+    /// Retryable errors are reported as [`NodeError::RetryLimitExceeded`], others unchanged.
+    pub(crate) fn final_error(&self, error: NodeError) -> NodeError {
+        if self.policy.should_retry(&error) {
+            NodeError::RetryLimitExceeded {
+                attempts: self.attempts,
+                message: error.to_string(),
+            }
+        } else {
+            error
+        }
+    }
+
+    /// Returns the wait before the next retry, computed in seconds so it cannot overflow
+    fn delay(&mut self) -> Duration {
+        let limit = Duration::from_secs(u64::from(MAX_WAIT_SECONDS));
+        let retry = self.attempts;
+        let secs = match &self.policy.backoff {
+            Backoff::Constant(wait) => wait.as_secs_f64(),
+            Backoff::Linear { initial, increment } => {
+                initial.as_secs_f64() + increment.as_secs_f64() * f64::from(retry)
+            }
+            Backoff::Exponential {
+                initial,
+                multiplier,
+                max,
+            } => (initial.as_secs_f64() * multiplier.max(0.0).powi(i32::from(retry)))
+                .min(max.as_secs_f64()),
+            Backoff::DecorrelatedJitter { base, cap } => {
+                let base = (*base).min(limit);
+                let upper = (self.previous * 3).max(base);
+                let range = (upper - base).as_millis() as u64;
+                let jitter = Duration::from_millis(self.next_random() % (range + 1));
+                (base + jitter).min(*cap).as_secs_f64()
+            }
+        };
+        Duration::try_from_secs_f64(secs).map_or(limit, |delay| delay.min(limit))
+    }
+
+    /// Splitmix64, good enough to spread out retries
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+fn seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(backoff: Backoff) -> Vec<Duration> {
+        let policy = RetryPolicy::new(10).unwrap().with_backoff(backoff);
+        let mut schedule = policy.schedule();
+        let error = NodeError::ExecutionError("failed".to_string());
+        std::iter::from_fn(|| schedule.next_delay(&error)).collect()
+    }
+
+    #[test]
+    fn linear_backoff_grows_by_increment() {
+        let delays = delays(Backoff::Linear {
+            initial: Duration::from_secs(1),
+            increment: Duration::from_secs(2),
+        });
+        assert_eq!(delays[..3], [1, 3, 5].map(Duration::from_secs));
+        assert_eq!(delays.len(), 10);
+    }
+
+    #[test]
+    fn exponential_backoff_stops_at_max() {
+        let delays = delays(Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            multiplier: 2.0,
+            max: Duration::from_millis(500),
+        });
+        assert_eq!(delays[..4], [100, 200, 400, 500].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn huge_backoffs_are_capped_instead_of_overflowing() {
+        let limit = Duration::from_secs(u64::from(MAX_WAIT_SECONDS));
+        let backoffs = [
+            Backoff::Constant(Duration::MAX),
+            Backoff::Linear {
+                initial: Duration::from_secs(1),
+                increment: Duration::MAX,
+            },
+            Backoff::Exponential {
+                initial: Duration::from_secs(1),
+                multiplier: f64::INFINITY,
+                max: Duration::MAX,
+            },
+            Backoff::Exponential {
+                initial: Duration::from_secs(60),
+                multiplier: 100.0,
+                max: Duration::MAX,
+            },
+            Backoff::DecorrelatedJitter {
+                base: Duration::MAX,
+                cap: Duration::MAX,
+            },
+        ];
+        for backoff in backoffs {
+            let delays = delays(backoff.clone());
+            assert_eq!(delays.len(), 10, "{backoff}");
+            assert!(delays[1..].iter().all(|d| *d == limit), "{backoff}");
+        }
+    }
+
+    #[test]
+    fn decorrelated_jitter_stays_within_bounds() {
+        let delays = delays(Backoff::DecorrelatedJitter {
+            base: Duration::from_millis(10),
+            cap: Duration::from_millis(200),
+        });
+        assert!(
+            delays
+                .iter()
+                .all(|d| (Duration::from_millis(10)..=Duration::from_millis(200)).contains(d))
+        );
+    }
+
+    #[test]
+    fn does_not_wait_past_max_elapsed() {
+        let policy = RetryPolicy::new(10)
+            .unwrap()
+            .with_backoff(Backoff::Constant(Duration::from_secs(1)))
+            .with_max_elapsed(Duration::from_millis(500));
+        let mut schedule = policy.schedule();
+        let error = NodeError::ExecutionError("failed".to_string());
+        assert_eq!(schedule.next_delay(&error), None);
+    }
+}
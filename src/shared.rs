@@ -0,0 +1,131 @@
+//! Shared data passed between nodes
+//!
+//! Nodes communicate through a key-value store of JSON values. The store is cheap to clone and
+//! every clone refers to the same data, so it can be handed to nodes running on other threads.
+use crate::core::NodeError;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Thread-safe key-value store shared by the nodes of a flow
+#[derive(Clone, Default)]
+pub struct SharedData {
+    inner: Arc<RwLock<HashMap<String, Value>>>,
+}
+
+impl SharedData {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value stored under `key` deserialized as `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, NodeError> {
+        match self.read().get(key) {
+            Some(value) => {
+                T::deserialize(value)
+                    .map(Some)
+                    .map_err(|e| NodeError::InvalidSharedData {
+                        key: key.to_string(),
+                        message: e.to_string(),
+                    })
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value stored under `key` deserialized as `T`, failing if it is missing
+    pub fn require<T: DeserializeOwned>(&self, key: &str) -> Result<T, NodeError> {
+        self.get(key)?
+            .ok_or_else(|| NodeError::MissingSharedData(key.to_string()))
+    }
+
+    /// Serializes `value` and stores it under `key`
+    pub fn set<T: Serialize>(&self, key: impl Into<String>, value: T) -> Result<(), NodeError> {
+        let key = key.into();
+        let value = serde_json::to_value(value).map_err(|e| NodeError::InvalidSharedData {
+            key: key.clone(),
+            message: e.to_string(),
+        })?;
+        self.write().insert(key, value);
+        Ok(())
+    }
+
+    /// Returns a copy of the raw value stored under `key`
+    pub fn get_value(&self, key: &str) -> Option<Value> {
+        self.read().get(key).cloned()
+    }
+
+    /// Stores a raw value under `key`, returning the previous value
+    pub fn set_value(&self, key: impl Into<String>, value: impl Into<Value>) -> Option<Value> {
+        self.write().insert(key.into(), value.into())
+    }
+
+    /// Removes the value stored under `key`
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        self.write().remove(key)
+    }
+
+    /// Returns true if a value is stored under `key`
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.read().contains_key(key)
+    }
+
+    /// Returns the keys currently in the store
+    pub fn keys(&self) -> Vec<String> {
+        self.read().keys().cloned().collect()
+    }
+
+    /// Returns the number of entries in the store
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Returns true if the store has no entries
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Returns a copy of every entry in the store
+    pub fn snapshot(&self) -> HashMap<String, Value> {
+        self.read().clone()
+    }
+
+    /// Returns an independent store holding a copy of every entry
+    pub fn fork(&self) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(self.snapshot())),
+        }
+    }
+
+    /// Runs `f` with exclusive access to the entries, for read-modify-write updates
+    pub fn update<R>(&self, f: impl FnOnce(&mut HashMap<String, Value>) -> R) -> R {
+        f(&mut self.write())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, Value>> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, Value>> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> From<HashMap<K, V>> for SharedData {
+    fn from(map: HashMap<K, V>) -> Self {
+        let map = map.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        Self {
+            inner: Arc::new(RwLock::new(map)),
+        }
+    }
+}
+
+impl fmt::Debug for SharedData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.read().fmt(f)
+    }
+}
@@ -0,0 +1,355 @@
+//! Token usage and cost accounting
+//!
+//! Every model call made by a node is recorded with
+//! [`ExecContext::record_usage`](crate::core::ExecContext::record_usage) in the run executing the
+//! node, so a [`FlowResult`](crate::flow::FlowResult), or the [`FlowError`](crate::flow::FlowError)
+//! of a failed run, reports what the run used per node and per model. Calls made by sub-flows
+//! count towards the run of the parent flow too. Costs are estimated from the [`PriceTable`] of
+//! the flow.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Price of a model, in a currency of your choice per million tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    /// Price per million prompt tokens
+    pub prompt: f64,
+    /// Price per million completion tokens
+    pub completion: f64,
+}
+
+impl ModelPrice {
+    /// Creates a price from the prices per million prompt and completion tokens
+    pub fn new(prompt: f64, completion: f64) -> Self {
+        Self { prompt, completion }
+    }
+
+    /// Returns the cost of the given numbers of tokens
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.prompt + completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// Prices of the models used by a flow
+///
+/// A model without a price of its own uses the price of the longest model name it starts with,
+/// so a price for `gpt-4o` also covers `gpt-4o-2024-08-06`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PriceTable {
+    prices: BTreeMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    /// Creates an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the price of `model`
+    pub fn with_price(mut self, model: impl Into<String>, price: ModelPrice) -> Self {
+        self.prices.insert(model.into(), price);
+        self
+    }
+
+    /// Returns true if no model has a price
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    /// Returns the price of `model`, if known
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        self.prices.get(model).copied().or_else(|| {
+            self.prices
+                .iter()
+                .filter(|(name, _)| model.starts_with(name.as_str()))
+                .max_by_key(|(name, _)| name.len())
+                .map(|(_, price)| *price)
+        })
+    }
+}
+
+/// Usage of one model by one node
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub node: String,
+    pub model: String,
+    /// Number of model calls
+    pub calls: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Estimated cost, zero if the model has no price
+    pub cost: f64,
+}
+
+/// Totals of a set of model calls
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub calls: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    /// Returns the number of prompt and completion tokens
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn add(&mut self, record: &UsageRecord) {
+        self.calls += record.calls;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.cost += record.cost;
+    }
+}
+
+/// The model usage of a run, per node and model
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// One record per node and model, sorted by node then model
+    pub records: Vec<UsageRecord>,
+    /// Models used without a price in the price table
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unpriced_models: Vec<String>,
+}
+
+impl UsageReport {
+    /// Returns true if no model was called
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns the totals of the whole run
+    pub fn total(&self) -> UsageTotals {
+        let mut total = UsageTotals::default();
+        self.records.iter().for_each(|record| total.add(record));
+        total
+    }
+
+    /// Returns the totals of every node that called a model
+    pub fn by_node(&self) -> BTreeMap<String, UsageTotals> {
+        self.group_by(|record| &record.node)
+    }
+
+    /// Returns the totals of every model called
+    pub fn by_model(&self) -> BTreeMap<String, UsageTotals> {
+        self.group_by(|record| &record.model)
+    }
+
+    /// Returns the totals of the node with the given name
+    pub fn node(&self, name: &str) -> UsageTotals {
+        self.by_node().remove(name).unwrap_or_default()
+    }
+
+    fn group_by(&self, key: impl Fn(&UsageRecord) -> &String) -> BTreeMap<String, UsageTotals> {
+        let mut groups: BTreeMap<String, UsageTotals> = BTreeMap::new();
+        for record in &self.records {
+            groups.entry(key(record).clone()).or_default().add(record);
+        }
+        groups
+    }
+}
+
+/// A single model call
+#[derive(Debug, Clone)]
+pub(crate) struct ModelCall {
+    pub(crate) node: String,
+    pub(crate) model: String,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+}
+
+/// Collects the model calls of a run and of the runs it is nested in
+///
+/// Each run has a list of its own, so runs on the same shared data do not count each other's
+/// calls. Nodes executed outside of a flow record into no list at all.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Ledger {
+    runs: Vec<Arc<Mutex<Vec<ModelCall>>>>,
+}
+
+impl Ledger {
+    /// Returns the ledger of a run nested in this one
+    pub(crate) fn child(&self) -> Self {
+        let mut runs = self.runs.clone();
+        runs.push(Arc::default());
+        Self { runs }
+    }
+
+    /// Records `call` in this run and every run it is nested in
+    pub(crate) fn record(&self, call: ModelCall) {
+        for run in &self.runs {
+            run.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(call.clone());
+        }
+    }
+
+    /// Returns the usage of the calls made by this run, priced with `prices`
+    pub(crate) fn report(&self, prices: &PriceTable) -> UsageReport {
+        match self.runs.last() {
+            Some(run) => report(&run.lock().unwrap_or_else(|e| e.into_inner()), prices),
+            None => UsageReport::default(),
+        }
+    }
+}
+
+/// Sums up `calls` per node and model, pricing them with `prices`
+fn report(calls: &[ModelCall], prices: &PriceTable) -> UsageReport {
+    let mut records: BTreeMap<(&str, &str), UsageRecord> = BTreeMap::new();
+    for call in calls {
+        let record = records
+            .entry((&call.node, &call.model))
+            .or_insert_with(|| UsageRecord {
+                node: call.node.clone(),
+                model: call.model.clone(),
+                ..UsageRecord::default()
+            });
+        record.calls += 1;
+        record.prompt_tokens += call.prompt_tokens;
+        record.completion_tokens += call.completion_tokens;
+    }
+    let mut unpriced_models = Vec::new();
+    for record in records.values_mut() {
+        match prices.price(&record.model) {
+            Some(price) => record.cost = price.cost(record.prompt_tokens, record.completion_tokens),
+            None if !unpriced_models.contains(&record.model) => {
+                unpriced_models.push(record.model.clone());
+            }
+            None => {}
+        }
+    }
+    unpriced_models.sort();
+    UsageReport {
+        records: records.into_values().collect(),
+        unpriced_models,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ExecContext, Node, NodeError, NodeLogic};
+    use crate::flow::{Flow, SubFlow};
+    use crate::llm::Usage;
+    use crate::shared::SharedData;
+    use serde_json::Value;
+    use std::sync::Barrier;
+
+    /// Records a call to `model`, after every other run holding `barrier` got there too
+    struct Call {
+        model: &'static str,
+        barrier: Option<Arc<Barrier>>,
+    }
+
+    impl NodeLogic for Call {
+        fn exec(&self, _prep_res: &Value, ctx: &ExecContext) -> Result<Value, NodeError> {
+            ctx.record_usage(
+                self.model,
+                Usage {
+                    prompt_tokens: 100,
+                    completion_tokens: 20,
+                    total_tokens: 120,
+                },
+            );
+            if let Some(barrier) = &self.barrier {
+                barrier.wait();
+            }
+            Ok(Value::Null)
+        }
+    }
+
+    struct Fail;
+
+    impl NodeLogic for Fail {
+        fn exec(&self, _prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+            Err(NodeError::ExecutionError("failed".into()))
+        }
+    }
+
+    fn call(name: &str, model: &'static str) -> Node {
+        Node::new(Some(name)).unwrap().with_logic(Call {
+            model,
+            barrier: None,
+        })
+    }
+
+    #[test]
+    fn prices_models_by_longest_prefix() {
+        let prices = PriceTable::new()
+            .with_price("gpt-4o", ModelPrice::new(2.5, 10.0))
+            .with_price("gpt-4o-mini", ModelPrice::new(0.15, 0.6));
+        assert_eq!(prices.price("gpt-4o-2024-08-06").unwrap().prompt, 2.5);
+        assert_eq!(prices.price("gpt-4o-mini-2024-07-18").unwrap().prompt, 0.15);
+        assert_eq!(prices.price("gpt-4"), None);
+    }
+
+    #[test]
+    fn reports_usage_per_node_and_model() {
+        let mut flow =
+            Flow::new().with_prices(PriceTable::new().with_price("a", ModelPrice::new(1.0, 2.0)));
+        let second = call("second", "b");
+        flow.start(call("first", "a").with_next(Arc::new(second)));
+        let usage = flow.run(SharedData::new()).unwrap().usage;
+
+        assert_eq!(usage.records.len(), 2);
+        assert_eq!(usage.node("first").prompt_tokens, 100);
+        assert_eq!(usage.by_model()["b"].completion_tokens, 20);
+        assert_eq!(usage.total().total_tokens(), 240);
+        assert_eq!(usage.total().cost, 140.0 / 1_000_000.0);
+        assert_eq!(usage.unpriced_models, ["b"]);
+    }
+
+    #[test]
+    fn failed_runs_report_their_usage() {
+        let mut flow = Flow::new();
+        let fail = Node::new(Some("fail")).unwrap().with_logic(Fail);
+        flow.start(call("first", "a").with_next(Arc::new(fail)));
+        let err = flow.run(SharedData::new()).unwrap_err();
+
+        assert!(err.to_string().contains("failed"), "{err}");
+        assert_eq!(err.usage.node("first").calls, 1);
+    }
+
+    #[test]
+    fn sub_flow_usage_counts_towards_the_parent_run() {
+        let mut inner = Flow::new();
+        inner.start(call("inner", "a"));
+        let mut flow = Flow::new();
+        let sub = SubFlow::new(inner).isolated(&[]).into_node("sub").unwrap();
+        flow.start(call("outer", "a").with_next(Arc::new(sub)));
+        let usage = flow.run(SharedData::new()).unwrap().usage;
+
+        assert_eq!(usage.total().calls, 2);
+        assert_eq!(usage.node("inner").calls, 1);
+    }
+
+    #[test]
+    fn concurrent_runs_on_the_same_data_keep_their_usage_apart() {
+        let barrier = Arc::new(Barrier::new(2));
+        let shared = SharedData::new();
+        let runs: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|model| {
+                let node = Node::new(Some("call")).unwrap().with_logic(Call {
+                    model,
+                    barrier: Some(barrier.clone()),
+                });
+                let mut flow = Flow::new();
+                flow.start(node);
+                let shared = shared.clone();
+                std::thread::spawn(move || flow.run(shared).unwrap().usage)
+            })
+            .collect();
+
+        for (run, model) in runs.into_iter().zip(["a", "b"]) {
+            let usage = run.join().unwrap();
+            assert_eq!(usage.total().calls, 1);
+            assert_eq!(usage.records[0].model, model);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Validation of flow graphs
+//!
+//! [`Flow::validate`] checks the structure of a flow before it runs, so a misconfigured graph is
+//! reported up front instead of failing part way through a run.
+use crate::core::{DEFAULT_ACTION, NodeError};
+use crate::flow::Flow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// A problem found in a flow graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /// The flow has no start node
+    MissingStartNode,
+    /// The start node is not a node of the flow
+    UnknownStartNode(String),
+    /// Different nodes were added under the same name
+    DuplicateNodeName(String),
+    /// An edge leaves from or leads to a node that is not part of the flow
+    DanglingEdge {
+        from: String,
+        action: String,
+        to: String,
+    },
+    /// No path leads from the start node to this node
+    UnreachableNode(String),
+    /// The nodes form a cycle connected only by default edges, so a run only leaves it when a
+    /// node returns an action without an edge
+    UnconditionedCycle(Vec<String>),
+}
+
+impl ValidationIssue {
+    /// Returns true if the issue prevents the flow from running correctly
+    ///
+    /// Unreachable nodes and cycles of default edges are only reported as warnings, since a node
+    /// in such a cycle may still end the run by returning an action without an edge.
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            ValidationIssue::UnreachableNode(_) | ValidationIssue::UnconditionedCycle(_)
+        )
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationIssue::MissingStartNode => write!(f, "flow has no start node"),
+            ValidationIssue::UnknownStartNode(name) => {
+                write!(f, "start node '{name}' is not part of the flow")
+            }
+            ValidationIssue::DuplicateNodeName(name) => {
+                write!(f, "more than one node is named '{name}'")
+            }
+            ValidationIssue::DanglingEdge { from, action, to } => {
+                write!(
+                    f,
+                    "edge '{from}' -[{action}]-> '{to}' refers to a missing node"
+                )
+            }
+            ValidationIssue::UnreachableNode(name) => {
+                write!(f, "node '{name}' cannot be reached from the start node")
+            }
+            ValidationIssue::UnconditionedCycle(cycle) => {
+                write!(
+                    f,
+                    "nodes '{}' loop through default edges only",
+                    cycle.join("' -> '")
+                )
+            }
+        }
+    }
+}
+
+/// The issues found when validating a flow
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// Returns every issue found
+    pub fn issues(&self) -> &[ValidationIssue] {
+        &self.issues
+    }
+
+    /// Returns the issues that prevent the flow from running correctly
+    pub fn errors(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues.iter().filter(|issue| issue.is_error())
+    }
+
+    /// Returns the issues that are only warnings
+    pub fn warnings(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues.iter().filter(|issue| !issue.is_error())
+    }
+
+    /// Returns true if no errors were found
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    /// Fails with [`NodeError::InvalidFlow`] if any errors were found
+    pub fn into_result(self) -> Result<(), NodeError> {
+        if self.is_valid() {
+            Ok(())
+        } else {
+            Err(NodeError::InvalidFlow(self))
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let issues: Vec<String> = self.issues.iter().map(ToString::to_string).collect();
+        write!(f, "{}", issues.join("; "))
+    }
+}
+
+impl Flow {
+    /// Checks the graph for structural problems without running it
+    ///
+    /// Detects a missing or unknown start node, duplicate node names, edges to or from missing
+    /// nodes, nodes unreachable from the start node and cycles only left by an action without an
+    /// edge.
+    pub fn validate(&self) -> ValidationReport {
+        let mut issues = Vec::new();
+
+        match self.start_name() {
+            None => issues.push(ValidationIssue::MissingStartNode),
+            Some(start) if self.node(start).is_none() => {
+                issues.push(ValidationIssue::UnknownStartNode(start.to_string()))
+            }
+            Some(_) => {}
+        }
+
+        let mut names = HashSet::new();
+        for node in self.nodes() {
+            if !names.insert(node.name()) {
+                issues.push(ValidationIssue::DuplicateNodeName(node.name().to_string()));
+            }
+        }
+
+        let edges = self.all_edges();
+        for &(from, action, to) in &edges {
+            if self.node(from).is_none() || self.node(to).is_none() {
+                issues.push(ValidationIssue::DanglingEdge {
+                    from: from.to_string(),
+                    action: action.to_string(),
+                    to: to.to_string(),
+                });
+            }
+        }
+
+        if let Some(start) = self.start_name().filter(|start| self.node(start).is_some()) {
+            let reachable = reachable_from(start, &edges);
+            let mut unreachable = HashSet::new();
+            for node in self.nodes() {
+                if !reachable.contains(node.name()) && unreachable.insert(node.name()) {
+                    issues.push(ValidationIssue::UnreachableNode(node.name().to_string()));
+                }
+            }
+        }
+
+        issues.extend(
+            unconditioned_cycles(self)
+                .into_iter()
+                .map(ValidationIssue::UnconditionedCycle),
+        );
+
+        ValidationReport { issues }
+    }
+}
+
+/// Returns the names of the nodes reachable from `start` by following edges
+fn reachable_from<'a>(start: &'a str, edges: &[(&'a str, &'a str, &'a str)]) -> HashSet<&'a str> {
+    let mut reachable = HashSet::from([start]);
+    let mut pending = VecDeque::from([start]);
+    while let Some(name) = pending.pop_front() {
+        for &(_, _, to) in edges.iter().filter(|(from, _, _)| *from == name) {
+            if reachable.insert(to) {
+                pending.push_back(to);
+            }
+        }
+    }
+    reachable
+}
+
+/// Finds cycles of nodes whose only edge is the default edge to the next node of the cycle
+fn unconditioned_cycles(flow: &Flow) -> Vec<Vec<String>> {
+    let only_default: HashMap<&str, &str> = flow
+        .nodes()
+        .iter()
+        .filter_map(|node| {
+            let edges = flow.edges(node.name())?;
+            match edges.get(DEFAULT_ACTION) {
+                Some(to) if edges.len() == 1 => Some((node.name(), to.as_str())),
+                _ => None,
+            }
+        })
+        .collect();
+
+    let mut cycles = Vec::new();
+    let mut done = HashSet::new();
+    for node in flow.nodes() {
+        let mut path: Vec<&str> = Vec::new();
+        let mut current = node.name();
+        while !done.contains(current) {
+            if let Some(pos) = path.iter().position(|&name| name == current) {
+                cycles.push(path[pos..].iter().map(|name| name.to_string()).collect());
+                break;
+            }
+            let Some(&next) = only_default.get(current) else {
+                break;
+            };
+            path.push(current);
+            current = next;
+        }
+        done.extend(path);
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ExecContext, Node, NodeLogic, NodeOutput};
+    use crate::shared::SharedData;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    /// Returns `done` once it has run `rounds` times
+    struct Think {
+        rounds: usize,
+    }
+
+    impl NodeLogic for Think {
+        fn exec(&self, _prep_res: &Value, _ctx: &ExecContext) -> Result<Value, NodeError> {
+            Ok(Value::Null)
+        }
+
+        fn post(
+            &self,
+            shared: &SharedData,
+            _prep_res: Value,
+            _exec_res: Value,
+        ) -> Result<NodeOutput, NodeError> {
+            let round = shared.get::<usize>("round")?.unwrap_or(0) + 1;
+            shared.set("round", round)?;
+            Ok(if round >= self.rounds {
+                NodeOutput::Action("done".to_string())
+            } else {
+                NodeOutput::default()
+            })
+        }
+    }
+
+    fn node(name: &str) -> Node {
+        Node::new(Some(name)).unwrap()
+    }
+
+    #[test]
+    fn default_cycle_is_a_warning_and_the_flow_runs() {
+        let mut flow = Flow::new();
+        flow.add_node(node("think").with_logic(Think { rounds: 2 }));
+        flow.add_node(node("act"));
+        flow.connect("think", "default", "act");
+        flow.connect("act", "default", "think");
+        flow.start_at("think");
+
+        let report = flow.validate();
+        assert!(report.is_valid());
+        assert_eq!(
+            report.warnings().collect::<Vec<_>>(),
+            [&ValidationIssue::UnconditionedCycle(vec![
+                "think".to_string(),
+                "act".to_string()
+            ])]
+        );
+        let result = flow.run(SharedData::new()).unwrap();
+        assert_eq!((result.last_node.as_str(), result.steps), ("think", 3));
+    }
+
+    #[test]
+    fn reports_structural_errors() {
+        let mut flow = Flow::new();
+        flow.add_node(node("a").with_next(Arc::new(node("b"))));
+        flow.add_node(node("a"));
+        flow.add_node(node("island"));
+        flow.connect("b", "default", "missing");
+        flow.start_at("a");
+
+        let report = flow.validate();
+        assert!(!report.is_valid());
+        let errors: Vec<_> = report.errors().cloned().collect();
+        assert!(errors.contains(&ValidationIssue::DuplicateNodeName("a".to_string())));
+        assert!(errors.contains(&ValidationIssue::DanglingEdge {
+            from: "b".to_string(),
+            action: "default".to_string(),
+            to: "missing".to_string(),
+        }));
+        assert!(
+            report
+                .warnings()
+                .any(|issue| *issue == ValidationIssue::UnreachableNode("island".to_string()))
+        );
+    }
+
+    #[test]
+    fn missing_start_node_is_an_error() {
+        let mut flow = Flow::new();
+        flow.add_node(node("a"));
+        assert_eq!(
+            flow.validate().issues(),
+            [ValidationIssue::MissingStartNode]
+        );
+        flow.start_at("b");
+        assert!(
+            flow.validate()
+                .issues()
+                .contains(&ValidationIssue::UnknownStartNode("b".to_string()))
+        );
+    }
+}